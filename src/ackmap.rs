@@ -1,21 +1,48 @@
-use mqtt_sn::defs::Message;
+use mqtt_sn::defs::*;
 use heapless::FnvIndexMap;
 use core::task::Waker;
 use core::future::Future;
 use core::pin::Pin;
 use core::task::{Context, Poll};
-use core::cell::RefCell;
-use embassy_sync::blocking_mutex::{Mutex, raw::NoopRawMutex};
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
 
+/// Acks are dispatched by msg_id, acks without one (CONNACK, PINGRESP, ...)
+/// use key 0. Shared between the receiving and the sending side of a client.
 pub struct AckMap<const C: usize> {
-    acks: Mutex<NoopRawMutex, RefCell<FnvIndexMap<u16, AckEntry, C>>>
+    acks: Mutex<CriticalSectionRawMutex, RefCell<FnvIndexMap<u16, AckEntry, C>>>,
+    ids: IdCounter,
 }
 
 type Error = AckMapError;
 
 impl<const C: usize> AckMap<C> {
     pub fn new() -> Self {
-        Self { acks: Mutex::new(RefCell::new(FnvIndexMap::<u16, AckEntry, C>::new())) }
+        Self {
+            acks: Mutex::new(RefCell::new(FnvIndexMap::<u16, AckEntry, C>::new())),
+            ids: IdCounter::new(),
+        }
+    }
+
+    /// Allocate a msg_id, never 0 nor one still waiting for its ack.
+    /// The id is expected until removed.
+    pub fn next_id(&self) -> Result<u16, Error> {
+        self.acks.lock(|acks| {
+            self.ids.insert(&mut acks.borrow_mut(), AckEntry::Expected).ok_or(Error::Full)
+        })
+    }
+
+    /// Announce a request, only acks for expected keys are kept
+    pub fn expect(&self, key: u16) -> Result<(), Error> {
+        self.acks.lock(|acks| {
+            let mut acks = acks.borrow_mut();
+            match acks.get_mut(&key) {
+                Some(AckEntry::Waker(_)) => (),
+                Some(entry) => *entry = AckEntry::Expected,
+                None => { acks.insert(key, AckEntry::Expected).map_err(|_| Error::Full)?; }
+            }
+            Ok(())
+        })
     }
 
     pub async fn insert(&self, key: u16, value: Message) -> Result<(), Error> {
@@ -29,25 +56,83 @@ impl<const C: usize> AckMap<C> {
                     *(current_value.unwrap()) = AckEntry::Value(value);
                     waker.wake();
                 },
-                None => { acks.insert(key, AckEntry::Value(value))?; }
+                Some(AckEntry::Expected) => { *(current_value.unwrap()) = AckEntry::Value(value); },
+                None => return Err(AckMapError::Unexpected)
             }
             Ok(())
         })
     }
 
+    /// Take a received ack without waiting, the key stays expected
+    pub fn take(&self, key: u16) -> Option<Message> {
+        self.acks.lock(|acks| {
+            let mut acks = acks.borrow_mut();
+            match acks.get_mut(&key) {
+                Some(entry @ AckEntry::Value(_)) => {
+                    match core::mem::replace(entry, AckEntry::Expected) {
+                        AckEntry::Value(msg) => Some(msg),
+                        _ => None
+                    }
+                },
+                _ => None
+            }
+        })
+    }
+
+    pub fn remove(&self, key: u16) {
+        self.acks.lock(|acks| { acks.borrow_mut().remove(&key); })
+    }
+
+    pub fn clear(&self) {
+        self.acks.lock(|acks| acks.borrow_mut().clear())
+    }
+
     pub fn wait(&self, key: u16) -> impl Future<Output = Result<Message, Error>>  + '_ {
         Ack { key, acks: &self.acks }
     }
 }
 
+/// Hands out ids in turn, skipping 0 and the keys of a map
+pub(crate) struct IdCounter {
+    last_id: Mutex<CriticalSectionRawMutex, Cell<u16>>,
+}
+
+impl IdCounter {
+    pub const fn new() -> Self {
+        Self { last_id: Mutex::new(Cell::new(0)) }
+    }
+
+    /// Insert `value` under the next free id, None if the map is full
+    pub fn insert<V, const C: usize>(&self, map: &mut FnvIndexMap<u16, V, C>, value: V) -> Option<u16> {
+        if map.len() == map.capacity() {
+            return None;
+        }
+        self.last_id.lock(|last_id| {
+            let mut id = last_id.get();
+            loop {
+                id = id.wrapping_add(1);
+                if id != 0 && !map.contains_key(&id) {
+                    break;
+                }
+            }
+            last_id.set(id);
+            map.insert(id, value).ok()?;
+            Some(id)
+        })
+    }
+}
+
+// Boxing the message needs an allocator, which no_std builds do not have
+#[allow(clippy::large_enum_variant)]
 enum AckEntry {
+    Expected,
     Value(Message),
     Waker(Waker),
 }
 
 pub struct Ack<'a, const C: usize> {
     key: u16,
-    acks: &'a Mutex<NoopRawMutex, RefCell<FnvIndexMap<u16, AckEntry, C>>>
+    acks: &'a Mutex<CriticalSectionRawMutex, RefCell<FnvIndexMap<u16, AckEntry, C>>>
 }
 
 impl<const C: usize> Future for Ack<'_, C> {
@@ -56,11 +141,19 @@ impl<const C: usize> Future for Ack<'_, C> {
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         self.acks.lock(|acks| {
             let mut acks = acks.borrow_mut();
-            match acks.remove(&self.key) {
-                Some(AckEntry::Value(msg)) => {
-                    Poll::Ready(Ok(msg))
+            match acks.get_mut(&self.key) {
+                // The key stays expected for further acks of the request
+                Some(entry @ AckEntry::Value(_)) => {
+                    match core::mem::replace(entry, AckEntry::Expected) {
+                        AckEntry::Value(msg) => Poll::Ready(Ok(msg)),
+                        _ => Poll::Ready(Err(AckMapError::Generic))
+                    }
                 },
-                _ => {
+                Some(entry) => {
+                    *entry = AckEntry::Waker(cx.waker().clone());
+                    Poll::Pending
+                },
+                None => {
                     acks.insert(self.key, AckEntry::Waker(cx.waker().clone()))?;
                     Poll::Pending
                 }
@@ -69,10 +162,43 @@ impl<const C: usize> Future for Ack<'_, C> {
     }
 }
 
+/// Key of the ack answering a request
+pub fn request_key(packet: &Message) -> u16 {
+    match packet {
+        Message::Publish(Publish { msg_id, .. })
+        | Message::PubRel(PubRel { msg_id })
+        | Message::Register(Register { msg_id, .. })
+        | Message::Subscribe(Subscribe { msg_id, .. })
+        | Message::Unsubscribe(Unsubscribe { msg_id, .. }) => *msg_id,
+        _ => 0
+    }
+}
+
+/// Key of a received ack, None if the message is not an ack
+pub fn ack_key(msg: &Message) -> Option<u16> {
+    match msg {
+        Message::PubAck(PubAck { msg_id, .. })
+        | Message::PubRec(PubRec { msg_id })
+        | Message::PubComp(PubComp { msg_id })
+        | Message::RegAck(RegAck { msg_id, .. })
+        | Message::SubAck(SubAck { msg_id, .. })
+        | Message::UnsubAck(UnsubAck { msg_id }) => Some(*msg_id),
+        Message::ConnAck(_)
+        | Message::WillTopicReq(_)
+        | Message::WillMsgReq(_)
+        | Message::WillTopicResp(_)
+        | Message::WillMsgResp(_)
+        | Message::PingResp(_)
+        | Message::Disconnect(_) => Some(0),
+        _ => None
+    }
+}
+
 pub enum AckMapError {
     Full,
     Generic,
-    IdUsed
+    IdUsed,
+    Unexpected
 }
 
 impl<K, V> From<(K, V)> for AckMapError {
@@ -80,3 +206,50 @@ impl<K, V> From<(K, V)> for AckMapError {
         AckMapError::Generic
     }
 }
+
+// Critical sections on the host come with embassy-time/std
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_id_in_turn() {
+        let acks = AckMap::<4>::new();
+        assert!(matches!(acks.next_id(), Ok(1)));
+        acks.remove(1);
+        assert!(matches!(acks.next_id(), Ok(2)));
+    }
+
+    #[test]
+    fn next_id_skips_0_on_wrap_around() {
+        let acks = AckMap::<4>::new();
+        acks.ids.last_id.lock(|last_id| last_id.set(u16::MAX - 1));
+        assert!(matches!(acks.next_id(), Ok(u16::MAX)));
+        assert!(matches!(acks.next_id(), Ok(1)));
+    }
+
+    #[test]
+    fn next_id_skips_ids_in_flight() {
+        let acks = AckMap::<8>::new();
+        assert!(acks.expect(2).is_ok());
+        assert!(acks.expect(3).is_ok());
+        assert!(matches!(acks.next_id(), Ok(1)));
+        assert!(matches!(acks.next_id(), Ok(4)));
+        // Wrapping around skips ids still in flight too
+        acks.ids.last_id.lock(|last_id| last_id.set(u16::MAX));
+        assert!(matches!(acks.next_id(), Ok(5)));
+    }
+
+    #[test]
+    fn next_id_full() {
+        let acks = AckMap::<4>::new();
+        // Acks without msg_id take up room too
+        assert!(acks.expect(0).is_ok());
+        for id in 1..4 {
+            assert!(matches!(acks.next_id(), Ok(next) if next == id));
+        }
+        assert!(matches!(acks.next_id(), Err(Error::Full)));
+        acks.remove(2);
+        assert!(matches!(acks.next_id(), Ok(4)));
+    }
+}
@@ -0,0 +1,167 @@
+use heapless::FnvIndexMap;
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
+use crate::ackmap::IdCounter;
+use crate::mqttsn::MqttSnClientError;
+
+type Error = MqttSnClientError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// Acked by the gateway, or sent for QoS 0 and -1
+    Delivered,
+    Rejected(Rejection),
+    /// Given up on by the client
+    Dropped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Congestion,
+    InvalidTopicId,
+    NotSupported,
+}
+
+/// Receives the outcome of messages carrying a request id
+pub trait DeliveryNotify {
+    fn complete(&self, request_id: u16, status: DeliveryStatus);
+}
+
+enum Slot {
+    Pending(Option<Waker>),
+    Done(DeliveryStatus),
+}
+
+/// Outcome of up to N messages in flight, shared between producer tasks and
+/// the client. Producers reserve an id with `track`, attach it with
+/// `MqttMessage::with_request_id` and await the outcome with `wait`. The
+/// slot of an id is only released by `wait`, every tracked id must be
+/// waited on.
+pub struct Deliveries<const N: usize> {
+    slots: Mutex<CriticalSectionRawMutex, RefCell<FnvIndexMap<u16, Slot, N>>>,
+    ids: IdCounter,
+}
+
+impl<const N: usize> Deliveries<N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: Mutex::new(RefCell::new(FnvIndexMap::new())),
+            ids: IdCounter::new(),
+        }
+    }
+
+    /// Reserve a request id to attach to a message, never 0 nor one
+    /// still pending
+    pub fn track(&self) -> Result<u16, Error> {
+        self.slots.lock(|slots| {
+            self.ids.insert(&mut slots.borrow_mut(), Slot::Pending(None)).ok_or(Error::DeliveriesFull)
+        })
+    }
+
+    /// Outcome of a tracked message, dropping the future releases the id
+    /// without waiting for it
+    pub fn wait(&self, request_id: u16) -> Delivery<'_, N> {
+        Delivery { deliveries: self, request_id, done: false }
+    }
+}
+
+impl<const N: usize> Default for Deliveries<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> DeliveryNotify for Deliveries<N> {
+    /// Only the first outcome of a request is kept
+    fn complete(&self, request_id: u16, status: DeliveryStatus) {
+        self.slots.lock(|slots| {
+            if let Some(slot) = slots.borrow_mut().get_mut(&request_id) {
+                if let Slot::Pending(waker) = slot {
+                    if let Some(waker) = waker.take() {
+                        waker.wake();
+                    }
+                    *slot = Slot::Done(status);
+                }
+            }
+        })
+    }
+}
+
+pub struct Delivery<'a, const N: usize> {
+    deliveries: &'a Deliveries<N>,
+    request_id: u16,
+    done: bool,
+}
+
+impl<const N: usize> Future for Delivery<'_, N> {
+    type Output = DeliveryStatus;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let poll = this.deliveries.slots.lock(|slots| {
+            let mut slots = slots.borrow_mut();
+            match slots.get_mut(&this.request_id) {
+                Some(Slot::Done(status)) => {
+                    let status = *status;
+                    slots.remove(&this.request_id);
+                    Poll::Ready(status)
+                },
+                Some(Slot::Pending(waker)) => {
+                    *waker = Some(cx.waker().clone());
+                    Poll::Pending
+                },
+                None => Poll::Ready(DeliveryStatus::Dropped)
+            }
+        });
+        this.done = poll.is_ready();
+        poll
+    }
+}
+
+impl<const N: usize> Drop for Delivery<'_, N> {
+    fn drop(&mut self) {
+        // Nobody waits for a later outcome
+        if !self.done {
+            self.deliveries.slots.lock(|slots| { slots.borrow_mut().remove(&self.request_id); })
+        }
+    }
+}
+
+// Critical sections on the host come with embassy-time/std
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use embassy_futures::{block_on, poll_once};
+
+    #[test]
+    fn wait_releases_id() {
+        let deliveries = Deliveries::<2>::new();
+        let first = deliveries.track().unwrap();
+        let second = deliveries.track().unwrap();
+        assert!(matches!(deliveries.track(), Err(Error::DeliveriesFull)));
+        deliveries.complete(first, DeliveryStatus::Delivered);
+        deliveries.complete(first, DeliveryStatus::Dropped);
+        assert_eq!(block_on(deliveries.wait(first)), DeliveryStatus::Delivered);
+        assert_eq!(deliveries.track().unwrap(), second + 1);
+    }
+
+    #[test]
+    fn dropped_wait_releases_id() {
+        let deliveries = Deliveries::<2>::new();
+        deliveries.track().unwrap();
+        let id = deliveries.track().unwrap();
+        let mut wait = deliveries.wait(id);
+        assert!(poll_once(&mut wait).is_pending());
+        drop(wait);
+        // The outcome arriving later is not kept
+        deliveries.complete(id, DeliveryStatus::Delivered);
+        assert_eq!(block_on(deliveries.wait(id)), DeliveryStatus::Dropped);
+        let id = deliveries.track().unwrap();
+        deliveries.complete(id, DeliveryStatus::Delivered);
+        drop(deliveries.wait(id));
+        assert!(deliveries.track().is_ok());
+    }
+}
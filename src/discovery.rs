@@ -0,0 +1,211 @@
+use heapless::FnvIndexMap;
+use no_std_net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use mqtt_sn::defs::*;
+use byte::{TryRead, TryWrite};
+use embassy_time::{with_timeout, Duration, Instant};
+use crate::socket::{SendBytesTo, ReceiveBytesFrom};
+use crate::mqttsn::MqttSnClientError;
+
+/// Advertise intervals missed before a gateway is considered lost
+const N_ADV: u64 = 3;
+
+#[cfg(feature = "std")]
+use log::*;
+
+#[cfg(feature = "no_std")]
+use defmt::*;
+
+type Error = MqttSnClientError;
+
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    pub gw_id: u8,
+    pub addr: SocketAddr,
+    /// Advertised interval in seconds, None if only seen in GWINFO
+    pub duration: Option<u16>,
+    pub last_seen: Instant,
+    pub lost: bool,
+}
+
+impl Gateway {
+    /// Advertising gateways are lost after N_ADV missed intervals
+    pub fn expired(&self) -> bool {
+        match self.duration {
+            Some(duration) => {
+                self.last_seen + Duration::from_secs(duration as u64 * N_ADV) < Instant::now()
+            },
+            None => false
+        }
+    }
+}
+
+/// Up to N gateways, N must be a power of two
+pub struct Gateways<const N: usize = 4> {
+    store: FnvIndexMap<u8, Gateway, N>
+}
+
+impl<const N: usize> Gateways<N> {
+    pub fn new() -> Self {
+        Self {
+            store: FnvIndexMap::<u8, Gateway, N>::new()
+        }
+    }
+    /// Add or refresh a gateway, a known advertise duration is kept
+    pub fn insert(
+        &mut self,
+        gw_id: u8,
+        addr: SocketAddr,
+        duration: Option<u16>
+    ) -> Result<(), Error> {
+        let duration = duration.or(self.get(gw_id).and_then(|gw| gw.duration));
+        let gateway = Gateway {
+            gw_id, addr, duration,
+            last_seen: Instant::now(),
+            lost: false
+        };
+        self.store.insert(gw_id, gateway).map_err(|_| Error::GatewayFailedInsert)?;
+        Ok(())
+    }
+    pub fn get(&self, gw_id: u8) -> Option<&Gateway> {
+        self.store.get(&gw_id)
+    }
+    pub fn remove(&mut self, gw_id: u8) -> Option<Gateway> {
+        self.store.remove(&gw_id)
+    }
+    /// Refresh a known gateway from ADVERTISE
+    pub fn advertised(&mut self, gw_id: u8, duration: u16) {
+        if let Some(gw) = self.store.get_mut(&gw_id) {
+            gw.duration = Some(duration);
+            gw.last_seen = Instant::now();
+            gw.lost = false;
+        }
+    }
+    /// Refresh a gateway that answered a request
+    pub fn seen(&mut self, gw_id: u8) {
+        if let Some(gw) = self.store.get_mut(&gw_id) {
+            gw.last_seen = Instant::now();
+        }
+    }
+    pub fn mark_lost(&mut self, gw_id: u8) {
+        if let Some(gw) = self.store.get_mut(&gw_id) {
+            gw.lost = true;
+        }
+    }
+    /// Give every gateway another chance, e.g. when all are lost
+    pub fn reset_lost(&mut self) {
+        for gw in self.store.values_mut() {
+            gw.lost = false;
+            gw.last_seen = Instant::now();
+        }
+    }
+    /// The most recently seen gateway that is not lost
+    pub fn select(&self) -> Option<&Gateway> {
+        self.store.values()
+            .filter(|gw| !gw.lost && !gw.expired())
+            .max_by_key(|gw| gw.last_seen)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Gateway> {
+        self.store.values()
+    }
+}
+
+impl<const N: usize> Default for Gateways<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Address of the gateway in a GWINFO, `gw_add` is only present if another
+/// client answered on its behalf and holds an IPv4 or IPv6 address,
+/// optionally followed by the port
+fn gateway_addr(gw_add: &[u8], sender: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = match gw_add.len() {
+        4 | 6 => Ipv4Addr::new(gw_add[0], gw_add[1], gw_add[2], gw_add[3]).into(),
+        16 | 18 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&gw_add[..16]);
+            Ipv6Addr::from(octets).into()
+        },
+        _ => return sender
+    };
+    let port = match gw_add.len() {
+        6 | 18 => u16::from_be_bytes([gw_add[gw_add.len() - 2], gw_add[gw_add.len() - 1]]),
+        _ => sender.port()
+    };
+    SocketAddr::new(ip, port)
+}
+
+pub struct Discovery<S> {
+    socket: S,
+    buffer: [u8; 64],
+}
+
+impl<S> Discovery<S>
+where
+    S: SendBytesTo + ReceiveBytesFrom
+{
+    pub fn new(socket: S) -> Self {
+        Self { socket, buffer: [0u8; 64] }
+    }
+
+    /// Broadcast SEARCHGW and collect GWINFO and ADVERTISE replies for `wait`
+    pub async fn search<const N: usize>(
+        &mut self,
+        broadcast: SocketAddr,
+        radius: u8,
+        wait: Duration,
+        gateways: &mut Gateways<N>
+    ) -> Result<(), Error> {
+        debug!("search gateway");
+        let packet = Message::SearchGw(SearchGw { radius });
+        let len = packet.try_write(&mut self.buffer, ())?;
+        self.socket.send_to(&self.buffer[..len], broadcast).await?;
+        self.listen(wait, gateways).await
+    }
+
+    /// Collect GWINFO and ADVERTISE for `wait` without searching
+    pub async fn listen<const N: usize>(
+        &mut self,
+        wait: Duration,
+        gateways: &mut Gateways<N>
+    ) -> Result<(), Error> {
+        match with_timeout(wait, async {
+            loop {
+                let (buf, addr) = self.socket.recv_from(&mut self.buffer).await?;
+                match Message::try_read(buf, ()) {
+                    Ok((Message::GwInfo(GwInfo { gw_id, gw_add }), _)) => {
+                        gateways.insert(gw_id, gateway_addr(&gw_add, addr), None)?
+                    },
+                    Ok((Message::Advertise(Advertise { gw_id, duration }), _)) => {
+                        gateways.insert(gw_id, addr, Some(duration))?
+                    },
+                    _ => ()
+                }
+            }
+        }).await {
+            Ok(result) => result,
+            // Listening for the full period is the normal outcome
+            Err(_) => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateway_addr_of_gwinfo() {
+        let sender: SocketAddr = "10.0.0.2:1883".parse().unwrap();
+        assert_eq!(gateway_addr(&[], sender), sender);
+        assert_eq!(gateway_addr(&[10, 0, 0, 1], sender), "10.0.0.1:1883".parse().unwrap());
+        assert_eq!(gateway_addr(&[10, 0, 0, 1, 0x07, 0x5c], sender), "10.0.0.1:1884".parse().unwrap());
+        let mut v6 = [0u8; 18];
+        v6[15] = 1;
+        v6[16..].copy_from_slice(&1884u16.to_be_bytes());
+        assert_eq!(gateway_addr(&v6, sender), "[::1]:1884".parse().unwrap());
+        assert_eq!(gateway_addr(&v6[..16], sender), "[::1]:1883".parse().unwrap());
+        // Not an address
+        assert_eq!(gateway_addr(&[1, 2, 3], sender), sender);
+    }
+}
@@ -1,28 +1,74 @@
-use nrf_modem::DtlsSocket;
-use crate::socket::{SocketError, SendBytes, ReceiveBytes};
+use nrf_modem::{DtlsSocket, PeerVerification};
+use crate::socket::{SocketError, SendBytes, ReceiveBytes, ConnectTo, SharedSocket};
+use no_std_net::SocketAddr;
+use heapless::String;
+use core::fmt::Write;
 
 impl From<nrf_modem::Error> for SocketError {
     fn from(_e: nrf_modem::Error) -> SocketError {
         SocketError::Generic
     }
 }
-pub struct DtlsSession(DtlsSocket);
+/// Can be split between a receiving and a sending task, e.g.
+/// `DtlsSession::new(DtlsConnection::connect(..).await?)`
+pub type DtlsSession = SharedSocket<DtlsConnection>;
 
-impl DtlsSession {
+pub struct DtlsConnection {
+    socket: DtlsSocket,
+    /// Settings to connect to other gateways with
+    security: Option<(PeerVerification, &'static [u32])>,
+}
+
+impl DtlsConnection {
+    /// Session that cannot be moved to another gateway, see `connect`
     pub fn new(socket: DtlsSocket) -> Self {
-        DtlsSession(socket)
+        DtlsConnection { socket, security: None }
+    }
+
+    /// Session with the gateway at `addr`, sessions with other gateways
+    /// are set up with the same settings
+    pub async fn connect(
+        addr: SocketAddr,
+        peer_verify: PeerVerification,
+        security_tags: &'static [u32]
+    ) -> Result<Self, SocketError> {
+        Ok(DtlsConnection {
+            socket: connect(addr, peer_verify, security_tags).await?,
+            security: Some((peer_verify, security_tags)),
+        })
     }
 }
 
-impl SendBytes for DtlsSession {
+async fn connect(
+    addr: SocketAddr,
+    peer_verify: PeerVerification,
+    security_tags: &[u32]
+) -> Result<DtlsSocket, SocketError> {
+    // Long enough for any IPv6 address
+    let mut host = String::<45>::new();
+    write!(host, "{}", addr.ip()).map_err(|_| SocketError::Generic)?;
+    Ok(DtlsSocket::connect(&host, addr.port(), peer_verify, security_tags).await?)
+}
+
+impl SendBytes for DtlsConnection {
     async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
-        self.0.send(buf).await?;
+        self.socket.send(buf).await?;
         Ok(())
     }
 }
 
-impl ReceiveBytes for DtlsSession {
+impl ReceiveBytes for DtlsConnection {
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
-        Ok(self.0.receive_from(buf).await?.0)
+        Ok(self.socket.receive_from(buf).await?.0)
     }
-}
\ No newline at end of file
+}
+
+impl ConnectTo for DtlsConnection {
+    /// Handshake with the new gateway, the old socket is closed once the
+    /// new one is up
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        let (peer_verify, security_tags) = self.security.ok_or(SocketError::Unsupported)?;
+        self.socket = connect(addr, peer_verify, security_tags).await?;
+        Ok(())
+    }
+}
@@ -7,7 +7,8 @@ use log::*;
 use std::error;
 use std::net::ToSocketAddrs;
 use serde_yaml::Value;
-use crate::socket::{SocketError, SendBytes, ReceiveBytes};
+use crate::socket::{SocketError, SendBytes, ReceiveBytes, ConnectTo, SharedSocket};
+use no_std_net::SocketAddr;
 use std::ffi::CString;
 
 openssl_errors! {
@@ -115,25 +116,46 @@ impl DtlsSocket {
         })
     }
 
-    pub async fn connect(&self,
+    /// The socket is kept by the session to connect to other gateways
+    pub async fn connect(self,
             addr: impl ToSocketAddrs,
         ) -> Result<DtlsSession, Box<dyn error::Error>> {
         info!("Connecting DTLS");
-        Ok(DtlsSession(self.client.connect(addr, Some(self.context.clone())).await?))
+        let session = self.client.connect(addr, Some(self.context.clone())).await?;
+        Ok(SharedSocket::new(DtlsConnection { socket: self, session }))
     }
 }
 
-pub struct DtlsSession(Session);
+/// Can be split between a receiving and a sending task
+pub type DtlsSession = SharedSocket<DtlsConnection>;
 
-impl SendBytes for DtlsSession {
+pub struct DtlsConnection {
+    socket: DtlsSocket,
+    session: Session,
+}
+
+impl SendBytes for DtlsConnection {
     async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
-        self.0.write(buf).await?;
+        self.session.write(buf).await?;
         Ok(())
     }
 }
 
-impl ReceiveBytes for DtlsSession {
+impl ReceiveBytes for DtlsConnection {
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
-        Ok(self.0.read(buf).await.map(|len| &mut buf[..len])?)
+        Ok(self.session.read(buf).await.map(|len| &mut buf[..len])?)
+    }
+}
+
+impl ConnectTo for DtlsConnection {
+    /// Handshake with the new gateway, the old session is dropped once
+    /// the new one is up
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        info!("Connecting DTLS");
+        self.session = self.socket.client
+            .connect(addr, Some(self.socket.context.clone()))
+            .await
+            .map_err(|_| SocketError::Generic)?;
+        Ok(())
     }
 }
@@ -6,7 +6,11 @@
 pub mod mqttsn;
 pub mod socket;
 pub mod topics;
-// pub(crate) mod ackmap;
+pub mod discovery;
+pub mod delivery;
+pub mod retry;
+pub mod store;
+pub(crate) mod ackmap;
 
 #[cfg(not(feature = "no_std"))]
 pub mod dtls_std;
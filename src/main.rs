@@ -1,5 +1,8 @@
-use mqttsn_client::mqttsn::{MqttSnClient, MqttMessage};
+use mqttsn_client::mqttsn::{MqttSnClient, MqttMessage, ClientEvent};
 use mqttsn_client::dtls_std::DtlsSocket;
+use mqttsn_client::discovery::{Discovery, Gateways};
+use mqttsn_client::socket::TokioUdp;
+use tokio::net::UdpSocket;
 use tokio::time::{sleep, Duration};
 use log::*;
 use embassy_sync::pubsub::PubSubChannel;
@@ -8,30 +11,62 @@ use std::net::ToSocketAddrs;
 
 static MQTT_RECV: PubSubChannel::<CriticalSectionRawMutex, MqttMessage, 10, 2, 1> = PubSubChannel::<CriticalSectionRawMutex, MqttMessage, 10, 2, 1>::new();
 static MQTT_SEND: PubSubChannel::<CriticalSectionRawMutex, MqttMessage, 10, 1, 2> = PubSubChannel::<CriticalSectionRawMutex, MqttMessage, 10, 1, 2>::new();
+static MQTT_EVENTS: PubSubChannel::<CriticalSectionRawMutex, ClientEvent, 10, 1, 1> = PubSubChannel::<CriticalSectionRawMutex, ClientEvent, 10, 1, 1>::new();
+
+const DISCOVERY_ADDR: &str = "255.255.255.255:1883";
+/// DTLS port of discovered gateways, replies come from their plain MQTT-SN port
+const DTLS_PORT: u16 = 3443;
+
+async fn discover_gateway(dtls_port: u16) -> std::net::SocketAddr {
+    let sock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+    sock.set_broadcast(true).unwrap();
+    let mut discovery = Discovery::new(TokioUdp(sock));
+    let mut gateways: Gateways = Gateways::new();
+    discovery.search(
+        DISCOVERY_ADDR.parse().unwrap(),
+        1,
+        embassy_time::Duration::from_secs(5),
+        &mut gateways
+    ).await.unwrap();
+    let mut addr = gateways.select().expect("no gateway found").addr;
+    addr.set_port(dtls_port);
+    addr
+}
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    let gateway = match std::env::var("MQTTSN_GATEWAY") {
+        Ok(host) => host.to_socket_addrs().unwrap().next().unwrap(),
+        Err(_) => {
+            let dtls_port = match std::env::var("MQTTSN_DTLS_PORT") {
+                Ok(port) => port.parse().unwrap(),
+                Err(_) => DTLS_PORT,
+            };
+            discover_gateway(dtls_port).await
+        },
+    };
     let socket = DtlsSocket::new().await.unwrap();
-    let session = socket.connect(
-        "illithid.duckdns.org:3443".to_socket_addrs().unwrap().next().unwrap()
-    ).await.unwrap();
+    let session = socket.connect(gateway).await.unwrap();
     info!("DTLS connected");
 
-    let mut mqtt_client = MqttSnClient::new(
+    let mut mqtt_client: MqttSnClient<_> = MqttSnClient::new(
         "test1",
         MQTT_SEND.dyn_subscriber().unwrap(),
         MQTT_RECV.dyn_publisher().unwrap(),
         session
-    ).unwrap();
+    ).unwrap()
+    .with_events(MQTT_EVENTS.dyn_publisher().unwrap());
     mqtt_client.connect(120).await.unwrap();
     info!("MQTT-SN connected");
 
-    mqtt_client.subscribe("test/recv".into()).await.unwrap();
+    mqtt_client.subscribe("test/recv").await.unwrap();
     debug!("subscribed");
 
     let mut mqtt_subscriber = MQTT_RECV.dyn_subscriber().unwrap();
+    let mut mqtt_events = MQTT_EVENTS.dyn_subscriber().unwrap();
     let mqtt_publisher = MQTT_SEND.dyn_publisher().unwrap();
+    let (mut mqtt_receiver, mut mqtt_sender) = mqtt_client.split();
     
     tokio::join!(
         async {
@@ -41,6 +76,12 @@ async fn main() {
                 dbg!(&msg);
             }
         },
+        async {
+            loop {
+                let event = mqtt_events.next_message_pure().await;
+                info!("event: {:?}", event);
+            }
+        },
         async {
             sleep(Duration::from_secs(12)).await;
             let msg = MqttMessage::new("test/send", "detterenpayload", Some(2)).unwrap();
@@ -49,6 +90,7 @@ async fn main() {
             let msg = MqttMessage::new("test/recv", "detterenpayload2", None).unwrap();
             mqtt_publisher.publish_immediate(msg);
         },
-        mqtt_client.run(10)
+        mqtt_receiver.run(),
+        mqtt_sender.run(10)
     );
 }
@@ -1,11 +1,19 @@
-use heapless::String;
-use crate::socket::{SendBytes, ReceiveBytes, SocketError};
+use heapless::{String, Vec, Deque, FnvIndexSet};
+use crate::socket::{SendBytes, ReceiveBytes, ConnectTo, Split, SocketError};
 use mqtt_sn::defs::*;
 use byte::{TryRead, TryWrite};
+use core::cell::RefCell;
+use no_std_net::SocketAddr;
+use embassy_sync::blocking_mutex::{Mutex, raw::CriticalSectionRawMutex};
 use embassy_sync::pubsub::subscriber::DynSubscriber;
 use embassy_sync::pubsub::publisher::DynPublisher;
-use embassy_time::{with_timeout, Duration, TimeoutError};
+use embassy_time::{with_timeout, Duration, TimeoutError, Timer};
 use crate::topics::Topics;
+use crate::discovery::Gateways;
+use crate::delivery::{DeliveryNotify, DeliveryStatus, Rejection};
+use crate::retry::{Request, Retry, RetryPolicy};
+use crate::store::{OutboundStore, RECORD_HEADER, RECORD_PROGRESS};
+use crate::ackmap::{AckMap, AckMapError, request_key, ack_key};
 
 #[cfg(feature = "std")]
 use log::*;
@@ -15,10 +23,45 @@ use defmt::*;
 
 const T_RETRY: u8 = 10;
 const N_RETRY: u8 = 10;
+const N_INBOUND: usize = 8;
+/// QoS 1 msg_ids remembered to recognise retransmissions
+const N_RECENT: usize = 8;
+const N_SUBSCRIPTIONS: usize = 8;
+const N_QUEUE: usize = 8;
+/// Publishes in flight at once, the ack map also holds REGISTER and
+/// requests without msg_id
+const N_WINDOW: usize = 4;
+const N_ACKS: usize = 8;
+
+/// Longest packet, with the three byte length field
+const MAX_PACKET: usize = 65535;
+/// Length, type, flags, topic id and msg id of a PUBLISH
+const PUBLISH_HEADER: usize = 9;
+/// Length, type, topic id and msg id of a REGISTER
+const REGISTER_HEADER: usize = 8;
+/// Longest payload and topic name the codec can carry
+const CODEC_PAYLOAD: usize = 256;
+const CODEC_TOPIC: usize = 256;
+
+/// Fields present in a stored message record
+const RECORD_QOS: u8 = 1;
+const RECORD_TOPIC_ID: u8 = 2;
+const RECORD_TOPIC_ID_TYPE: u8 = 4;
+const RECORD_REQUEST_ID: u8 = 8;
+/// Handshake stage of a stored message, only advanced by clearing bits
+const STAGE_NEW: u8 = 0xff;
+const STAGE_SENT: u8 = 0xfe;
+const STAGE_RECEIVED: u8 = 0xfc;
+/// Where a record keeps its sequence number
+const RECORD_SEQ: usize = RECORD_PROGRESS + 7;
+
+/// QoS -1, coded as 0b11 in the flags field. Can be published without a
+/// connection, but only to predefined or short topic ids.
+pub const QOS_MINUS_ONE: u8 = 3;
 
 type Error = MqttSnClientError;
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
 #[repr(u8)]
 pub enum TopicIdType {
     Id,
@@ -26,9 +69,38 @@ pub enum TopicIdType {
     Short
 }
 
+/// Client states of the MQTT-SN spec, sleeping clients alternate between
+/// asleep and awake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "no_std", derive(Format))]
+pub enum ClientState {
+    Disconnected,
+    Active,
+    Asleep,
+    Awake,
+    Lost,
+}
+
+/// Reported to the application next to received messages
+#[derive(Debug, Clone)]
+pub enum ClientEvent<const TOPIC: usize = 256> {
+    Connected,
+    Disconnected,
+    Sleeping,
+    /// Retransmissions exhausted without an answer from the gateway
+    Lost,
+    GatewayLost(u8),
+    PublishRejected(String<TOPIC>),
+    SubscribeFailed(String<TOPIC>),
+    Error(MqttSnClientError),
+}
+
 pub enum AckResult {
     Success,
     TopicId(u16),
+    Rejected(ReturnCode),
+    WillTopicReq,
+    WillMsgReq,
     None
 }
 
@@ -44,90 +116,793 @@ impl TryFrom<u8> for TopicIdType {
     }
 }
 
-pub struct MqttSnClient<S> {
-    client_id: ClientId,
-    msg_id: MsgId,
+/// Sized by the packet buffer, the longest topic name and payload, and the
+/// number of topics and gateways known, which must be powers of two. Topic
+/// names and payloads are limited by the codec to 256 bytes.
+pub struct MqttSnClient<
+    S,
+    const BUF: usize = 1024,
+    const TOPIC: usize = 256,
+    const PAYLOAD: usize = 256,
+    const TOPICS: usize = 16,
+    const GATEWAYS: usize = 4,
+> {
     socket: S,
-    topics: Topics,
-    rx: DynSubscriber<'static, MqttMessage>,
-    tx: DynPublisher<'static, MqttMessage>,
-    buffer: [u8; 1024],
+    shared: Shared<TOPIC, PAYLOAD, TOPICS, GATEWAYS>,
+    session: Session<BUF, TOPIC, PAYLOAD>,
+}
+
+/// State used by both the receiving and the sending side of a client
+pub struct Shared<const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> {
+    topics: Mutex<CriticalSectionRawMutex, RefCell<Topics<TOPICS, TOPIC>>>,
+    gateways: Mutex<CriticalSectionRawMutex, RefCell<Gateways<GATEWAYS>>>,
+    inbound: Mutex<CriticalSectionRawMutex, RefCell<FnvIndexSet<u16, N_INBOUND>>>,
+    recent: Mutex<CriticalSectionRawMutex, RefCell<Deque<u16, N_RECENT>>>,
+    acks: AckMap<N_ACKS>,
+    tx: DynPublisher<'static, MqttMessage<TOPIC, PAYLOAD>>,
+    events: Option<DynPublisher<'static, ClientEvent<TOPIC>>>,
 }
 
-impl<S> MqttSnClient<S>
+/// State of the sending side
+struct Session<const BUF: usize, const TOPIC: usize, const PAYLOAD: usize> {
+    client_id: ClientId,
+    rx: DynSubscriber<'static, MqttMessage<TOPIC, PAYLOAD>>,
+    deliveries: Option<&'static dyn DeliveryNotify>,
+    store: Option<&'static mut dyn OutboundStore<TOPIC, PAYLOAD>>,
+    subscriptions: Vec<String<TOPIC>, N_SUBSCRIPTIONS>,
+    will: Option<Will<TOPIC, PAYLOAD>>,
+    clean_session: bool,
+    congestion_backoff: Duration,
+    retry: RetryPolicy,
+    gateway: Option<u8>,
+    queue: Deque<MqttMessage<TOPIC, PAYLOAD>, N_QUEUE>,
+    state: ClientState,
+    window: usize,
+    buffer: [u8; BUF],
+}
+
+impl<const BUF: usize, const TOPIC: usize, const PAYLOAD: usize> Session<BUF, TOPIC, PAYLOAD> {
+    /// Take the queued message at `index` out, the others keep their order
+    fn remove(&mut self, index: usize) -> Option<MqttMessage<TOPIC, PAYLOAD>> {
+        let mut removed = None;
+        for i in 0..self.queue.len() {
+            if let Some(msg) = self.queue.pop_front() {
+                if i == index {
+                    removed = Some(msg);
+                } else {
+                    let _ = self.queue.push_back(msg);
+                }
+            }
+        }
+        removed
+    }
+
+    /// Queue stored messages that are not queued yet, oldest first
+    fn refill(&mut self) {
+        if let Some(store) = self.store.as_deref_mut() {
+            while !self.queue.is_full() {
+                let queue = &self.queue;
+                let slot = match store.oldest(&|slot| queue.iter().any(|msg| msg.slot == Some(slot))) {
+                    Some(slot) => slot,
+                    None => break
+                };
+                match store.load(slot) {
+                    Ok(Some(mut msg)) => {
+                        msg.slot = Some(slot);
+                        let _ = self.queue.push_back(msg);
+                    },
+                    Ok(None) => break,
+                    Err(_) => {
+                        warn!("dropping unreadable message");
+                        if store.remove(slot).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<S, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> MqttSnClient<S, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>
 where
     S: SendBytes + ReceiveBytes
 {
+    /// Capacities that cannot be used with MQTT-SN
+    const CHECK: () = {
+        assert!(BUF <= MAX_PACKET, "buffer longer than the longest packet");
+        assert!(PAYLOAD + PUBLISH_HEADER <= BUF, "payload does not fit the buffer");
+        assert!(TOPIC + REGISTER_HEADER <= BUF, "topic name does not fit the buffer");
+        assert!(PAYLOAD <= CODEC_PAYLOAD, "payload longer than the codec supports");
+        assert!(TOPIC <= CODEC_TOPIC, "topic name longer than the codec supports");
+    };
+
     pub fn new(
         client_id: &str,
-        rx: DynSubscriber<'static, MqttMessage>,
-        tx: DynPublisher<'static, MqttMessage>,
+        rx: DynSubscriber<'static, MqttMessage<TOPIC, PAYLOAD>>,
+        tx: DynPublisher<'static, MqttMessage<TOPIC, PAYLOAD>>,
         socket: S
-    ) -> Result<MqttSnClient<S>, Error> {
+    ) -> Result<Self, Error> {
+        let () = Self::CHECK;
         Ok(MqttSnClient {
-            client_id: client_id.into(),
-            msg_id: MsgId {last_id: 0},
-            topics: Topics::new(),
-            socket, rx, tx,
-            buffer: [0u8; 1024]
+            socket,
+            shared: Shared {
+                topics: Mutex::new(RefCell::new(Topics::new())),
+                gateways: Mutex::new(RefCell::new(Gateways::new())),
+                inbound: Mutex::new(RefCell::new(FnvIndexSet::new())),
+                recent: Mutex::new(RefCell::new(Deque::new())),
+                acks: AckMap::new(),
+                tx,
+                events: None,
+            },
+            session: Session {
+                client_id: client_id.into(),
+                rx,
+                deliveries: None,
+                store: None,
+                subscriptions: Vec::new(),
+                will: None,
+                clean_session: false,
+                congestion_backoff: Duration::from_secs(T_RETRY.into()),
+                retry: RetryPolicy::new(
+                    Retry::new(Duration::from_secs(T_RETRY.into()), N_RETRY)
+                ),
+                gateway: None,
+                queue: Deque::new(),
+                state: ClientState::Disconnected,
+                window: 1,
+                buffer: [0u8; BUF]
+            }
         })
     }
 
-    pub async fn run(
-        &mut self,
-        sleep: u16,
+    /// Topics predefined between client and gateway, used without REGISTER
+    pub fn with_predefined_topics(
+        self,
+        predefined: &'static [(&'static str, u16)]
+    ) -> Result<Self, Error> {
+        self.shared.topics(|topics| {
+            for (topic, id) in predefined {
+                topics.insert(String::try_from(*topic)?, TopicIdType::PreDef, *id)?;
+            }
+            Ok::<(), Error>(())
+        })?;
+        Ok(self)
+    }
+
+    /// Ask the gateway to drop subscriptions and registrations on connect,
+    /// subscriptions are then replayed by the client
+    pub fn with_clean_session(mut self, clean_session: bool) -> Self {
+        self.session.clean_session = clean_session;
+        self
+    }
+
+    /// Wait before retrying a publish rejected with congestion
+    pub fn with_congestion_backoff(mut self, backoff: Duration) -> Self {
+        self.session.congestion_backoff = backoff;
+        self
+    }
+
+    /// Timeouts and retransmissions of requests, by default T_RETRY
+    /// seconds and N_RETRY retransmissions for all
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.session.retry = retry;
+        self
+    }
+
+    /// Will published by the gateway if the client is lost
+    pub fn with_will(mut self, will: Will<TOPIC, PAYLOAD>) -> Self {
+        self.session.will = Some(will);
+        self
+    }
+
+    pub fn with_events(mut self, events: DynPublisher<'static, ClientEvent<TOPIC>>) -> Self {
+        self.shared.events = Some(events);
+        self
+    }
+
+    /// Outcome of messages with a request id, usually a static `Deliveries`
+    pub fn with_deliveries(mut self, deliveries: &'static dyn DeliveryNotify) -> Self {
+        self.session.deliveries = Some(deliveries);
+        self
+    }
+
+    /// Keep QoS 1 and 2 messages in `store` until acked. Messages stored
+    /// before a reboot are queued again, those that do not fit the queue
+    /// wait in the store like messages arriving while the queue is full.
+    pub fn with_store(
+        mut self,
+        store: &'static mut dyn OutboundStore<TOPIC, PAYLOAD>
+    ) -> Self {
+        self.session.store = Some(store);
+        self.session.refill();
+        self
+    }
+
+    /// Queued publishes sent before waiting for their acks, 1 to N_WINDOW.
+    /// Only queued publishes are pipelined, SUBSCRIBE, UNSUBSCRIBE and
+    /// REGISTER still wait for their ack before the next request is sent.
+    pub fn with_window(mut self, window: usize) -> Result<Self, Error> {
+        if !(1..=N_WINDOW).contains(&window) {
+            return Err(Error::InvalidWindow);
+        }
+        self.session.window = window;
+        Ok(self)
+    }
+
+    /// Known gateways, e.g. from configuration or `Discovery`
+    pub fn with_gateways(mut self, gateways: Gateways<GATEWAYS>) -> Self {
+        *self.shared.gateways.get_mut().get_mut() = gateways;
+        self
+    }
+
+    pub fn gateways(&mut self) -> &mut Gateways<GATEWAYS> {
+        self.shared.gateways.get_mut().get_mut()
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.session.state
+    }
+
+    /// Sending side reading its acks from the client's own socket
+    fn sender(&mut self) -> MqttSnSender<'_, Direct<'_, S>, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS> {
+        MqttSnSender {
+            link: Direct(&mut self.socket),
+            shared: &self.shared,
+            session: &mut self.session,
+        }
+    }
+
+    /// Receive one packet, waiting for the timeout of the retry policy
+    pub async fn receive(&mut self) -> Result<(), Error> {
+        with_timeout(
+            self.session.retry.default_retry().timeout(0),
+            self.shared.receive(&mut self.socket, &mut self.session.buffer)
+        ).await?
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
+        self.sender().send(msg).await
+    }
+
+    pub async fn send_ack<F>(
+        &mut self, packet: Message, ack_handler: F
+    ) -> Result<AckResult, Error>
+    where
+        F: Fn(Message) -> AckResult
+    {
+        self.sender().send_ack(packet, ack_handler).await
+    }
+
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.sender().ping().await
+    }
+
+    pub async fn publish(&mut self, msg: MqttMessage<TOPIC, PAYLOAD>) -> Result<(), Error> {
+        self.sender().publish(msg).await
+    }
+
+    pub async fn connect(&mut self, duration: u16) -> Result<(), Error> {
+        self.sender().connect(duration).await
+    }
+
+    pub async fn restore_session(&mut self) -> Result<(), Error> {
+        self.sender().restore_session().await
+    }
+
+    pub async fn update_will_topic(&mut self, will: Option<Will<TOPIC, PAYLOAD>>) -> Result<(), Error> {
+        self.sender().update_will_topic(will).await
+    }
+
+    pub async fn update_will_message(&mut self, message: &str) -> Result<(), Error> {
+        self.sender().update_will_message(message).await
+    }
+
+    pub async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.sender().subscribe(topic).await
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.sender().unsubscribe(topic).await
+    }
+
+    pub async fn disconnect(&mut self, duration: Option<u16>) -> Result<(), Error> {
+        self.sender().disconnect(duration).await
+    }
+}
+
+impl<S, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> MqttSnClient<S, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>
+where
+    S: SendBytes + ReceiveBytes + ConnectTo
+{
+    /// Inbound traffic is only read while waiting for an ack,
+    /// see `split` to keep listening in between
+    pub async fn run(&mut self, sleep: u16) {
+        self.sender().run(sleep).await
+    }
+
+    pub async fn select_gateway(&mut self) -> Result<u8, Error> {
+        self.sender().select_gateway().await
+    }
+}
+
+impl<S, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> MqttSnClient<S, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>
+where
+    S: SendBytes + ReceiveBytes + Split
+{
+    /// Receiving half that listens all the time and a sending half driven
+    /// from another task. The receiver hands acks over to the sender.
+    pub fn split(&mut self) -> (
+        MqttSnReceiver<'_, S::Receiver<'_>, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>,
+        MqttSnSender<'_, Dispatched<S::Sender<'_>>, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>
     ) {
-        loop {
-            match with_timeout(
-                Duration::from_secs(sleep.into()),
-                self.rx.next_message_pure()
-            ).await {
-                Ok(msg) => {
-                    // Handle message received from the user (via DynSubscriber)
-                    self.connect(sleep).await.unwrap();
-                    self.publish(msg).await.unwrap();
-                    // Publish aditional msg if queued
-                    while let Some(msg) = self.rx.try_next_message_pure() {
-                        self.publish(msg).await.unwrap();
+        let (receiver, sender) = self.socket.split();
+        (
+            MqttSnReceiver {
+                socket: receiver,
+                shared: &self.shared,
+                buffer: [0u8; BUF],
+            },
+            MqttSnSender {
+                link: Dispatched(sender),
+                shared: &self.shared,
+                session: &mut self.session,
+            }
+        )
+    }
+}
+
+impl<const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> Shared<TOPIC, PAYLOAD, TOPICS, GATEWAYS> {
+    fn topics<R>(&self, f: impl FnOnce(&mut Topics<TOPICS, TOPIC>) -> R) -> R {
+        self.topics.lock(|topics| f(&mut topics.borrow_mut()))
+    }
+
+    fn gateways<R>(&self, f: impl FnOnce(&mut Gateways<GATEWAYS>) -> R) -> R {
+        self.gateways.lock(|gateways| f(&mut gateways.borrow_mut()))
+    }
+
+    fn report(&self, event: ClientEvent<TOPIC>) {
+        if let Some(events) = &self.events {
+            events.publish_immediate(event);
+        }
+    }
+
+    /// Receive one packet. Publishes and requests from the gateway are
+    /// handled here, acks are dispatched by msg_id to the waiting request.
+    async fn receive<R>(&self, socket: &mut R, buffer: &mut [u8]) -> Result<(), Error>
+    where
+        R: SendBytes + ReceiveBytes
+    {
+        let msg = match Message::try_read(socket.recv(buffer).await?, ()) {
+            Ok((msg, _)) => msg,
+            _ => return Err(MqttSnClientError::AckError)
+        };
+        let reply = match msg {
+            Message::Publish(msg) => self.recieve_publish(msg)?,
+            Message::PubRel(msg) => Some(self.receive_pubrel(msg)),
+            Message::Register(msg) => Some(self.receive_register(msg)),
+            Message::Advertise(Advertise { gw_id, duration }) => {
+                self.gateways(|gateways| gateways.advertised(gw_id, duration));
+                None
+            },
+            msg => {
+                // Acks nobody is waiting for are dropped
+                if let Some(key) = ack_key(&msg) {
+                    let _ = self.acks.insert(key, msg).await;
+                }
+                None
+            }
+        };
+        match reply {
+            Some(reply) => send_packet(socket, buffer, reply).await,
+            None => Ok(())
+        }
+    }
+
+    fn recieve_publish(&self, msg: Publish) -> Result<Option<Message>, Error> {
+        let msg_id = msg.msg_id;
+        let dup = msg.flags.dup();
+        let msg = self.topics(|topics| MqttMessage::from_publish(msg, topics))?;
+        let reply = match msg.qos {
+            Some(1) => {
+                // A retransmission is acked again, but only delivered if
+                // the first transmission was not received
+                let ack = msg.get_ack().map(Message::PubAck);
+                if self.received(msg_id, dup) {
+                    debug!("duplicate publish dropped");
+                    return Ok(ack);
+                }
+                ack
+            },
+            Some(2) => {
+                // msg_id is held until PUBREL, retransmissions are only acked
+                let duplicate = self.inbound.lock(|inbound| {
+                    let mut inbound = inbound.borrow_mut();
+                    if inbound.contains(&msg_id) {
+                        return Ok(true);
                     }
-                    self.disconnect(Some(sleep)).await.unwrap();
-                },
-                _ => {
-                    self.ping().await.unwrap();
+                    inbound.insert(msg_id).map(|_| false).map_err(|_| Error::InboundFull)
+                })?;
+                if duplicate {
+                    debug!("duplicate publish dropped");
+                    return Ok(Some(Message::PubRec(PubRec { msg_id })));
                 }
+                Some(Message::PubRec(PubRec { msg_id }))
+            },
+            _ => None
+        };
+        self.tx.publish_immediate(msg);
+        Ok(reply)
+    }
+
+    /// Remember a QoS 1 msg_id, true if it is a retransmission of one seen before
+    fn received(&self, msg_id: u16, dup: bool) -> bool {
+        self.recent.lock(|recent| {
+            let mut recent = recent.borrow_mut();
+            if dup && recent.iter().any(|id| *id == msg_id) {
+                return true;
             }
+            if recent.is_full() {
+                recent.pop_front();
+            }
+            let _ = recent.push_back(msg_id);
+            false
+        })
+    }
+
+    fn receive_pubrel(&self, msg: PubRel) -> Message {
+        // Always answer, the gateway may retransmit PUBREL after we completed
+        self.inbound.lock(|inbound| inbound.borrow_mut().remove(&msg.msg_id));
+        Message::PubComp(PubComp { msg_id: msg.msg_id })
+    }
+
+    /// Topic names the gateway assigns an id to, e.g. matches of a wildcard subscription
+    fn receive_register(&self, msg: Register) -> Message {
+        debug!("register from gateway");
+        let code = match String::try_from(msg.topic_name.as_str()) {
+            Ok(topic) => match self.topics(|topics| topics.insert(topic, TopicIdType::Id, msg.topic_id)) {
+                Ok(()) => ReturnCode::Accepted,
+                // Topic store is full
+                Err(_) => ReturnCode::Rejected(RejectedReason::Congestion),
+            },
+            Err(_) => ReturnCode::Rejected(RejectedReason::NotSupported),
+        };
+        Message::RegAck(RegAck {
+            topic_id: msg.topic_id,
+            msg_id: msg.msg_id,
+            code
+        })
+    }
+}
+
+async fn send_packet<T: SendBytes>(
+    socket: &mut T, buffer: &mut [u8], msg: Message
+) -> Result<(), Error> {
+    let len = msg.try_write(buffer, ())?;
+    socket.send(&buffer[..len]).await?;
+    Ok(())
+}
+
+/// How the sending side gets its acks
+pub trait AckLink: SendBytes {
+    async fn ack<const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize>(
+        &mut self, shared: &Shared<TOPIC, PAYLOAD, TOPICS, GATEWAYS>, key: u16, buffer: &mut [u8]
+    ) -> Result<Message, Error>;
+}
+
+/// Acks are read from the socket while waiting, other inbound traffic is
+/// handled meanwhile
+pub struct Direct<'a, S>(&'a mut S);
+
+/// Acks are handed over by a `MqttSnReceiver`
+pub struct Dispatched<T>(T);
+
+impl<S: SendBytes> SendBytes for Direct<'_, S> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.0.send(buf).await
+    }
+}
+
+impl<S: ConnectTo> ConnectTo for Direct<'_, S> {
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.0.connect_to(addr).await
+    }
+}
+
+impl<S> AckLink for Direct<'_, S>
+where
+    S: SendBytes + ReceiveBytes
+{
+    async fn ack<const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize>(
+        &mut self, shared: &Shared<TOPIC, PAYLOAD, TOPICS, GATEWAYS>, key: u16, buffer: &mut [u8]
+    ) -> Result<Message, Error> {
+        loop {
+            if let Some(msg) = shared.acks.take(key) {
+                return Ok(msg);
+            }
+            let _ = shared.receive(&mut *self.0, buffer).await;
         }
     }
+}
 
-    pub async fn receive(&mut self) -> Result<Option<Message>, Error> {
+impl<T: SendBytes> SendBytes for Dispatched<T> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.0.send(buf).await
+    }
+}
+
+impl<T: ConnectTo> ConnectTo for Dispatched<T> {
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.0.connect_to(addr).await
+    }
+}
+
+impl<T> AckLink for Dispatched<T>
+where
+    T: SendBytes
+{
+    async fn ack<const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize>(
+        &mut self, shared: &Shared<TOPIC, PAYLOAD, TOPICS, GATEWAYS>, key: u16, _buffer: &mut [u8]
+    ) -> Result<Message, Error> {
+        Ok(shared.acks.wait(key).await?)
+    }
+}
+
+/// Receiving half of a split client, publishes and requests from the
+/// gateway are handled as soon as they arrive
+pub struct MqttSnReceiver<'a, R, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> {
+    socket: R,
+    shared: &'a Shared<TOPIC, PAYLOAD, TOPICS, GATEWAYS>,
+    buffer: [u8; BUF],
+}
+
+impl<R, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> MqttSnReceiver<'_, R, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>
+where
+    R: SendBytes + ReceiveBytes
+{
+    pub async fn run(&mut self) {
         loop {
-            match Message::try_read(
-                with_timeout(
-                    Duration::from_secs(T_RETRY.into()),
-                    self.socket.recv(&mut self.buffer)).await??, ()
-                ) {
-                Ok((Message::Publish(msg), _)) => self.recieve_publish(msg).await?,
-                Ok((msg, _)) => return Ok(Some(msg)),
-                _ => return Err(MqttSnClientError::AckError)
+            if let Err(e) = self.receive().await {
+                self.shared.report(ClientEvent::Error(e));
             }
         }
     }
 
-    async fn recieve_publish(&mut self, msg: Publish) -> Result<(), Error> {
-        let msg = MqttMessage::from_publish(msg, &self.topics)?;
-        if msg.qos > Some(0) {
-            if let Some(ack) = msg.get_ack() {
-                self.send(Message::PubAck(ack)).await?;
+    pub async fn receive(&mut self) -> Result<(), Error> {
+        self.shared.receive(&mut self.socket, &mut self.buffer).await
+    }
+}
+
+/// Sending half of a client, publishes the queue and makes requests
+pub struct MqttSnSender<'a, L, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> {
+    link: L,
+    shared: &'a Shared<TOPIC, PAYLOAD, TOPICS, GATEWAYS>,
+    session: &'a mut Session<BUF, TOPIC, PAYLOAD>,
+}
+
+impl<L, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> MqttSnSender<'_, L, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>
+where
+    L: AckLink
+{
+    pub fn state(&self) -> ClientState {
+        self.session.state
+    }
+
+    fn report(&self, event: ClientEvent<TOPIC>) {
+        self.shared.report(event);
+    }
+
+    fn notify(&self, request_id: Option<u16>, status: DeliveryStatus) {
+        if let (Some(deliveries), Some(request_id)) = (self.session.deliveries, request_id) {
+            deliveries.complete(request_id, status);
+        }
+    }
+
+    /// Stored messages that do not fit the queue wait in the store. Others
+    /// make room by dropping the oldest message only kept in RAM, or are
+    /// dropped if there is none.
+    fn enqueue(&mut self, mut msg: MqttMessage<TOPIC, PAYLOAD>) {
+        if matches!(msg.qos, Some(1 | 2)) {
+            msg.slot = self.keep(&msg);
+        }
+        if self.session.queue.is_full() {
+            if msg.slot.is_some() {
+                // Queued from the store once the queue drains
+                return;
+            }
+            warn!("queue full, dropping message");
+            let dropped = self.session.queue.iter()
+                .position(|queued| queued.slot.is_none())
+                .and_then(|index| self.session.remove(index));
+            match dropped {
+                Some(dropped) => self.notify(dropped.request_id, DeliveryStatus::Dropped),
+                None => {
+                    self.notify(msg.request_id, DeliveryStatus::Dropped);
+                    return;
+                }
+            }
+        }
+        let _ = self.session.queue.push_back(msg);
+    }
+
+    /// Store a message until acked, it is only kept in RAM if that fails
+    fn keep(&mut self, msg: &MqttMessage<TOPIC, PAYLOAD>) -> Option<u16> {
+        let store = self.session.store.as_deref_mut()?;
+        match store.store(msg) {
+            Ok(slot) => Some(slot),
+            Err(e) => {
+                warn!("message not stored");
+                self.shared.report(ClientEvent::Error(e));
+                None
+            }
+        }
+    }
+
+    /// Store the msg_id and handshake stage of a QoS 2 message before they
+    /// are used, it must not be published again after a reboot
+    fn remember(&mut self, msg: &MqttMessage<TOPIC, PAYLOAD>) {
+        if msg.qos != Some(2) {
+            return;
+        }
+        if let (Some(store), Some(slot)) = (self.session.store.as_deref_mut(), msg.slot) {
+            if let Err(e) = store.update(slot, msg) {
+                self.shared.report(ClientEvent::Error(e));
+            }
+        }
+    }
+
+    /// Keep the msg_id and handshake stage of the queued message at `index`
+    fn progress(&mut self, index: usize, msg: &MqttMessage<TOPIC, PAYLOAD>) {
+        if let Some(queued) = self.session.queue.iter_mut().nth(index) {
+            queued.msg_id = msg.msg_id;
+            queued.received = msg.received;
+        }
+    }
+
+    /// Remove a message that is acked, rejected or dropped from the store
+    fn forget(&mut self, msg: &MqttMessage<TOPIC, PAYLOAD>) {
+        if let (Some(store), Some(slot)) = (self.session.store.as_deref_mut(), msg.slot) {
+            if let Err(e) = store.remove(slot) {
+                self.shared.report(ClientEvent::Error(e));
+            }
+        }
+    }
+
+    /// Publish queued messages, a message is only dequeued once sent or
+    /// given up on after a fatal error
+    async fn flush(&mut self) -> Result<(), Error> {
+        let result = self.flush_window().await;
+        if result.is_err() {
+            // Acks for the rest of the window are no longer waited for
+            self.shared.acks.clear();
+        }
+        result
+    }
+
+    /// Up to `window` publishes are sent before waiting for their acks.
+    /// The msg_id and handshake stage are kept on the queued message, so the
+    /// next attempt after an error resumes where this one stopped.
+    async fn flush_window(&mut self) -> Result<(), Error> {
+        while !self.session.queue.is_empty() {
+            let mut batch: Vec<MqttMessage<TOPIC, PAYLOAD>, N_WINDOW> = self.session.queue.iter()
+                .take(self.session.window)
+                .cloned()
+                .collect();
+            // msg_ids kept from earlier attempts are not handed out again
+            for msg in &batch {
+                if let Some(msg_id) = msg.msg_id {
+                    self.shared.acks.expect(msg_id)?;
+                }
+            }
+            let mut in_flight: Vec<Option<Publish>, N_WINDOW> = Vec::new();
+            let mut result = Ok(());
+            for (index, msg) in batch.iter_mut().enumerate() {
+                let sent = self.send_publish(msg).await;
+                self.progress(index, msg);
+                match sent {
+                    Ok(publish) => { let _ = in_flight.push(publish); },
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            let mut sent = batch.iter_mut().zip(in_flight);
+            // Queue index of the first sent message not confirmed, and of a
+            // message that failed while being confirmed
+            let mut index = 0;
+            let mut failed = None;
+            if result.is_ok() {
+                for (msg, publish) in sent.by_ref() {
+                    result = match self.confirm(msg, publish).await {
+                        // Rejections are retried one message at a time
+                        Err(e) if e.rejection().is_some() => self.deliver(msg).await,
+                        result => {
+                            self.published(msg, &result);
+                            result
+                        }
+                    };
+                    self.progress(0, msg);
+                    if result.is_err() {
+                        failed = Some(0);
+                        index = 1;
+                        break;
+                    }
+                    if let Some(msg) = self.session.queue.pop_front() {
+                        self.forget(&msg);
+                        self.session.refill();
+                    }
+                }
+            }
+            if let Err(e) = result {
+                // The rest of the window was sent, messages acked meanwhile
+                // are done and must not be published again
+                for (msg, publish) in sent {
+                    if self.drain(msg, publish) {
+                        if let Some(msg) = self.session.remove(index) {
+                            self.published(&msg, &Ok(()));
+                            self.forget(&msg);
+                        }
+                    } else {
+                        self.progress(index, msg);
+                        index += 1;
+                    }
+                }
+                // Only the message that failed is given up on, a send that
+                // failed comes after the messages sent before it
+                if !e.is_transient() {
+                    warn!("dropping message");
+                    if let Some(msg) = self.session.remove(failed.unwrap_or(index)) {
+                        self.notify(msg.request_id, DeliveryStatus::Dropped);
+                        self.forget(&msg);
+                    }
+                }
+                self.session.refill();
+                return Err(e);
             }
         }
-        self.tx.publish_immediate(msg);
         Ok(())
     }
 
+    /// Take an ack already received for a sent message without waiting,
+    /// true if the message is done
+    fn drain(&self, msg: &mut MqttMessage<TOPIC, PAYLOAD>, publish: Option<Publish>) -> bool {
+        let publish = match publish {
+            Some(publish) => publish,
+            // Sent with QoS 0 or -1, unless only PUBREL is left
+            None => return !msg.received
+        };
+        match self.shared.acks.take(publish.msg_id) {
+            Some(Message::PubAck(PubAck { code: ReturnCode::Accepted, .. })) => {
+                publish.flags.qos() == 1
+            },
+            Some(Message::PubRec(_)) if publish.flags.qos() == 2 => {
+                msg.received = true;
+                false
+            },
+            _ => false
+        }
+    }
+
+    /// ADVERTISE does not reach a connected socket, an ack from the current
+    /// gateway shows it is alive as well
+    fn gateway_seen(&self) {
+        if let Some(gw_id) = self.session.gateway {
+            self.shared.gateways(|gateways| gateways.seen(gw_id));
+        }
+    }
+
+    /// Current gateway has stopped advertising
+    fn gateway_expired(&self) -> bool {
+        self.session.gateway
+            .and_then(|gw_id| self.shared.gateways(|gateways| {
+                gateways.get(gw_id).map(|gw| gw.expired())
+            }))
+            .unwrap_or(false)
+    }
+
     pub async fn send(&mut self, msg: Message) -> Result<(), Error> {
-        let len = msg.try_write(&mut self.buffer, ())?;
-        self.socket.send(&self.buffer[..len]).await?;
-        Ok(())
+        send_packet(&mut self.link, &mut self.session.buffer, msg).await
     }
 
     pub async fn send_ack<F>(
@@ -136,36 +911,90 @@ where
     where
         F: Fn(Message) -> AckResult
     {
-        let len = packet.try_write(&mut self.buffer, ())?;
-        
-        for _ in 1..N_RETRY {
-            self.socket.send(&self.buffer[..len]).await?;
+        self.send_request(&packet).await?;
+        self.wait_ack(packet, ack_handler).await
+    }
+
+    /// Send a packet that is answered with an ack
+    async fn send_request(&mut self, packet: &Message) -> Result<(), Error> {
+        let key = request_key(packet);
+        self.shared.acks.expect(key)?;
+        let result = self.send(packet.clone()).await;
+        if result.is_err() {
+            self.shared.acks.remove(key);
+        }
+        result
+    }
+
+    /// Wait for the ack of a sent request, retransmitting it on timeout.
+    /// Acks for other requests received meanwhile are kept for them.
+    async fn wait_ack<F>(
+        &mut self, mut packet: Message, ack_handler: F
+    ) -> Result<AckResult, Error>
+    where
+        F: Fn(Message) -> AckResult
+    {
+        let key = request_key(&packet);
+        let retry = self.session.retry.get(Request::of(&packet));
+        for attempt in 0..=retry.retries() {
+            if attempt > 0 {
+                // Retransmitted PUBLISH and SUBSCRIBE must carry the DUP flag,
+                // REGISTER has no flags
+                match packet {
+                    Message::Publish(Publish { ref mut flags, .. })
+                    | Message::Subscribe(Subscribe { ref mut flags, .. }) => flags.set_dup(true),
+                    _ => ()
+                }
+                if let Err(e) = self.send(packet.clone()).await {
+                    self.shared.acks.remove(key);
+                    return Err(e);
+                }
+            }
 
             match with_timeout(
-                Duration::from_secs(T_RETRY.into()),
+                retry.timeout(attempt),
                 async {
-                    loop{
-                        if let Ok(Some(msg)) = self.receive().await {
-                            match ack_handler(msg) {
-                                AckResult::None => (),
-                                result => return result,
-                            }
+                    loop {
+                        let msg = match self.link.ack(self.shared, key, &mut self.session.buffer).await {
+                            Ok(msg) => msg,
+                            Err(e) => return Err(e)
+                        };
+                        match ack_handler(msg) {
+                            AckResult::None => (),
+                            result => return Ok(result),
                         }
                     }
                 }).await
             {
-                Ok(result) => return Ok(result),
+                Ok(result) => {
+                    self.shared.acks.remove(key);
+                    if result.is_ok() {
+                        self.gateway_seen();
+                    }
+                    return result;
+                },
                 _ => ()
             }
         }
+        self.shared.acks.remove(key);
+        // Retransmissions exhausted, the gateway is considered lost
+        self.session.state = ClientState::Lost;
+        self.report(ClientEvent::Lost);
         Err(Error::AckError)
     }
 
+    /// Keep-alive while active. While asleep the client is awake until
+    /// PINGRESP, buffered messages from the gateway are delivered meanwhile.
     pub async fn ping(&mut self) -> Result<(), Error>{
         debug!("ping");
-        let packet = Message::PingReq(PingReq {
-            client_id: self.client_id.clone()
-        });
+        let client_id = match self.session.state {
+            ClientState::Asleep | ClientState::Awake => {
+                self.session.state = ClientState::Awake;
+                self.session.client_id.clone()
+            },
+            _ => "".into()
+        };
+        let packet = Message::PingReq(PingReq { client_id });
         let ack_handler = |msg| {
             match msg {
                 Message::PingResp(_) => AckResult::Success,
@@ -174,59 +1003,196 @@ where
         };
 
         self.send_ack(packet, ack_handler).await?;
+        if self.session.state == ClientState::Awake {
+            self.session.state = ClientState::Asleep;
+        }
         Ok(())
     }
 
-    pub async fn publish(&mut self, msg: MqttMessage) -> Result<(), Error> {
+    /// Invalid topic ids are registered again and congestion is backed off
+    /// from, both are retried. Other rejections are returned as errors.
+    pub async fn publish(&mut self, mut msg: MqttMessage<TOPIC, PAYLOAD>) -> Result<(), Error> {
+        self.deliver(&mut msg).await
+    }
+
+    /// Publish with retries, `msg` keeps the progress of its handshake
+    async fn deliver(&mut self, msg: &mut MqttMessage<TOPIC, PAYLOAD>) -> Result<(), Error> {
         debug!("publish");
-        let mut flags = Flags::default();
-        if let Some(qos) = msg.qos {
-            flags.set_qos(qos)
+        let mut result = Err(Error::AckError);
+        for _ in 0..=self.session.retry.get(Request::Publish).retries() {
+            result = self.try_publish(msg).await;
+            match result {
+                // Registration lost by the gateway
+                Err(Error::InvalidTopicId)
+                    if self.shared.topics(|topics| topics.remove_registered(&msg.topic)) => {
+                    warn!("invalid topic id, registering again");
+                },
+                Err(Error::Congestion) => {
+                    warn!("congestion, backing off");
+                    Timer::after(self.session.congestion_backoff).await;
+                },
+                _ => break
+            }
         }
+        self.published(msg, &result);
+        result
+    }
 
-        let topic_id;
-        if let Some((topic_type, id)) = self.topics.get_by_topic(&msg.topic) {
-            topic_id = *id;
-            flags.set_topic_id_type(*topic_type as u8);
-        } else {
-            topic_id = self.register(&msg.topic).await?;
-            self.topics.insert(msg.topic, TopicIdType::Id, topic_id)?;
+    /// Report the outcome of a publish, failures other than rejections
+    /// are left to the retry in `run`
+    fn published(&self, msg: &MqttMessage<TOPIC, PAYLOAD>, result: &Result<(), Error>) {
+        match result {
+            Ok(()) => self.notify(msg.request_id, DeliveryStatus::Delivered),
+            Err(e) => if let Some(rejection) = e.rejection() {
+                self.notify(msg.request_id, DeliveryStatus::Rejected(rejection));
+                self.report(ClientEvent::PublishRejected(msg.topic.clone()));
+            }
         }
-        let next_msg_id = self.msg_id.next();
+    }
 
-        let mut data = PublishData::new();
-        data.push_str(&msg.payload)?;
-        let packet = Message::Publish(
-            Publish {flags, topic_id, msg_id: next_msg_id, data}
-        );
+    async fn try_publish(&mut self, msg: &mut MqttMessage<TOPIC, PAYLOAD>) -> Result<(), Error> {
+        let publish = self.send_publish(msg).await?;
+        self.confirm(msg, publish).await
+    }
 
-        // Get ACK for QoS 1 & 2
+    /// Send the PUBLISH of a message, nothing if only PUBREL is left.
+    /// Returns the publish to confirm for QoS 1 and 2.
+    async fn send_publish(&mut self, msg: &mut MqttMessage<TOPIC, PAYLOAD>) -> Result<Option<Publish>, Error> {
+        if msg.received {
+            return Ok(None);
+        }
+        let publish = self.prepare_publish(msg).await?;
         match msg.qos {
-            Some(qos) if qos > 0 => {
-                let ack_handler = |msg| {
-                    match msg {
-                        Message::PubAck(PubAck {
-                            msg_id, code: ReturnCode::Accepted, ..
-                        }) if msg_id == next_msg_id => AckResult::Success,
-                        _ => AckResult::None
-                    }
-                };
-                self.send_ack(packet, ack_handler).await?;
+            Some(1 | 2) => {
+                self.send_request(&Message::Publish(publish.clone())).await?;
+                Ok(Some(publish))
             },
             _ => {
-                self.send(packet.into()).await?;
+                self.send(Message::Publish(publish)).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Wait for the acks of a message sent with `send_publish`
+    async fn confirm(
+        &mut self, msg: &mut MqttMessage<TOPIC, PAYLOAD>, publish: Option<Publish>
+    ) -> Result<(), Error> {
+        match (publish, msg.msg_id) {
+            (Some(publish), _) => self.confirm_publish(msg, publish).await,
+            // PUBREC was received before, a new PUBLISH would be delivered twice
+            (None, Some(msg_id)) if msg.received => self.release(msg_id).await,
+            _ => Ok(())
+        }
+    }
+
+    /// Resolve the topic, registering it if needed, and build the packet
+    async fn prepare_publish(&mut self, msg: &mut MqttMessage<TOPIC, PAYLOAD>) -> Result<Publish, Error> {
+        let mut flags = Flags::default();
+        if let Some(qos) = msg.qos {
+            flags.set_qos(qos)
+        }
+
+        let no_connect = msg.qos == Some(QOS_MINUS_ONE);
+        let entry = match (msg.topic_id_type, msg.topic_id) {
+            (Some(TopicIdType::PreDef), Some(id)) => Some((TopicIdType::PreDef, id)),
+            _ => self.shared.topics(|topics| topics.get_by_topic(&msg.topic))
+        };
+        let (topic_type, topic_id) = match entry {
+            // Registration requires a connection
+            Some((TopicIdType::Id, _)) | None if no_connect => {
+                return Err(Error::TopicNotRegistered)
+            },
+            Some(entry) => entry,
+            None => {
+                let id = self.register(&msg.topic).await?;
+                self.shared.topics(|topics| topics.insert(msg.topic.clone(), TopicIdType::Id, id))?;
+                (TopicIdType::Id, id)
+            }
+        };
+        flags.set_topic_id_type(topic_type as u8);
+        // The codec carries data as text, binary payloads cannot be sent
+        let data = PublishData::try_from(msg.payload_str()?)?;
+        // Only QoS 1 and 2 are acked by msg_id. Allocated last, the id is
+        // held in the ack map until the publish is sent and acked. A message
+        // sent before keeps its msg_id and is marked as duplicate.
+        let next_msg_id = match (msg.qos, msg.msg_id) {
+            (Some(1 | 2), Some(msg_id)) => {
+                flags.set_dup(true);
+                msg_id
+            },
+            (Some(1 | 2), None) => {
+                let msg_id = self.shared.acks.next_id()?;
+                msg.msg_id = Some(msg_id);
+                self.remember(msg);
+                msg_id
+            },
+            _ => 0
+        };
+        Ok(Publish {flags, topic_id, msg_id: next_msg_id, data})
+    }
+
+    /// Wait for the acks of a sent QoS 1 or 2 publish.
+    /// Get ACK for QoS 1, PUBREC/PUBREL/PUBCOMP for QoS 2.
+    /// Both are rejected with PUBACK.
+    async fn confirm_publish(
+        &mut self, msg: &mut MqttMessage<TOPIC, PAYLOAD>, publish: Publish
+    ) -> Result<(), Error> {
+        let qos = publish.flags.qos();
+        let next_msg_id = publish.msg_id;
+        let ack_handler = |msg| {
+            match msg {
+                Message::PubAck(PubAck {
+                    msg_id, code: ReturnCode::Accepted, ..
+                }) if qos == 1 && msg_id == next_msg_id => AckResult::Success,
+                Message::PubRec(PubRec {
+                    msg_id
+                }) if qos == 2 && msg_id == next_msg_id => AckResult::Success,
+                Message::PubAck(PubAck {
+                    msg_id, code, ..
+                }) if msg_id == next_msg_id => AckResult::Rejected(code),
+                _ => AckResult::None
+            }
+        };
+        match self.wait_ack(Message::Publish(publish), ack_handler).await? {
+            AckResult::Rejected(code) => {
+                // Not kept by the gateway, the next attempt is a new publish
+                msg.msg_id = None;
+                Err(code.into())
             },
+            _ if qos == 2 => {
+                msg.received = true;
+                self.remember(msg);
+                self.release(next_msg_id).await
+            },
+            _ => Ok(())
         }
+    }
+
+    /// Second half of the QoS 2 handshake, PUBREL is retransmitted until PUBCOMP
+    async fn release(&mut self, msg_id: u16) -> Result<(), Error> {
+        debug!("release");
+        let packet = Message::PubRel(PubRel { msg_id });
+        let ack_handler = |msg| {
+            match msg {
+                Message::PubComp(PubComp {
+                    msg_id: id
+                }) if id == msg_id => AckResult::Success,
+                _ => AckResult::None
+            }
+        };
+
+        self.send_ack(packet, ack_handler).await?;
         Ok(())
     }
 
-    async fn register(&mut self, topic: &String<256>) -> Result<u16, Error> {
+    async fn register(&mut self, topic: &String<TOPIC>) -> Result<u16, Error> {
         debug!("register");
-        let msg_id = self.msg_id.next();
+        let msg_id = self.shared.acks.next_id()?;
         let packet = Message::Register(Register {
             topic_id: 0,
             msg_id,
-            topic_name: TopicName::from(&topic)
+            topic_name: TopicName::from(topic.as_str())
         });
         let ack_handler = |msg| {
             match msg {
@@ -236,7 +1202,7 @@ where
                 _ => AckResult::None
             }
         };
-        
+
         match self.send_ack(packet, ack_handler).await {
             Ok(AckResult::TopicId(id)) => return Ok(id),
             _ => Err(Error::AckError)
@@ -245,58 +1211,191 @@ where
 
     pub async fn connect(&mut self, duration: u16) -> Result<(), Error> {
         debug!("connect");
+        let mut flags = Flags::default();
+        flags.set_will(self.session.will.is_some());
+        flags.set_clean_session(self.session.clean_session);
         let packet = Message::Connect(Connect {
-            flags: Flags::default(),
+            flags,
             duration,
-            client_id: self.client_id.clone()
+            client_id: self.session.client_id.clone()
         });
         let ack_handler = |msg| {
             match msg {
                 Message::ConnAck(
                     ConnAck{code: ReturnCode::Accepted}
                 ) => AckResult::Success,
+                Message::WillTopicReq(_) => AckResult::WillTopicReq,
+                Message::WillMsgReq(_) => AckResult::WillMsgReq,
+                _ => AckResult::None
+            }
+        };
+
+        let mut result = self.send_ack(packet, ack_handler).await?;
+        // Gateway requests will topic and message before CONNACK
+        loop {
+            let packet = match (result, &self.session.will) {
+                (AckResult::Success, _) => break,
+                (AckResult::WillTopicReq, Some(will)) => Message::WillTopic(WillTopic {
+                    flags: will.flags(),
+                    topic: TopicName::from(&will.topic)
+                }),
+                (AckResult::WillMsgReq, Some(will)) => Message::WillMsg(WillMsg {
+                    msg: will.data()?
+                }),
+                _ => return Err(Error::AckError)
+            };
+            result = self.send_ack(packet, ack_handler).await?;
+        }
+        self.session.state = ClientState::Active;
+        self.report(ClientEvent::Connected);
+        if self.session.clean_session {
+            self.restore_session().await?;
+        }
+        Ok(())
+    }
+
+    /// Drop topic ids assigned by the gateway and replay subscriptions.
+    /// Also used when the gateway has lost the session state.
+    pub async fn restore_session(&mut self) -> Result<(), Error> {
+        debug!("restore session");
+        self.shared.topics(|topics| topics.clear_registered());
+        self.shared.inbound.lock(|inbound| inbound.borrow_mut().clear());
+        self.shared.recent.lock(|recent| recent.borrow_mut().clear());
+        for topic in self.session.subscriptions.clone() {
+            self.subscribe(&topic).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the will topic, None deletes the will
+    pub async fn update_will_topic(&mut self, will: Option<Will<TOPIC, PAYLOAD>>) -> Result<(), Error> {
+        debug!("update will topic");
+        let packet = Message::WillTopicUpd(match &will {
+            Some(will) => WillTopicUpd {
+                flags: will.flags(),
+                topic: TopicName::from(&will.topic)
+            },
+            None => WillTopicUpd {
+                flags: Flags::default(),
+                topic: TopicName::from("")
+            }
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::WillTopicResp(WillTopicResp {
+                    code: ReturnCode::Accepted
+                }) => AckResult::Success,
+                _ => AckResult::None
+            }
+        };
+
+        self.send_ack(packet, ack_handler).await?;
+        self.session.will = will;
+        Ok(())
+    }
+
+    pub async fn update_will_message(&mut self, message: &str) -> Result<(), Error> {
+        debug!("update will message");
+        let mut will = self.session.will.clone().ok_or(Error::NoWill)?;
+        will.message = String::try_from(message)?;
+        let packet = Message::WillMsgUpd(WillMsgUpd {
+            msg: will.data()?
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::WillMsgResp(WillMsgResp {
+                    code: ReturnCode::Accepted
+                }) => AckResult::Success,
                 _ => AckResult::None
             }
         };
 
         self.send_ack(packet, ack_handler).await?;
+        self.session.will = Some(will);
         Ok(())
     }
 
     pub async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
         debug!("subscribe");
         let mut flags = Flags::default();
-        let topic_id;
-        let topic = String::<256>::try_from(topic)?;
-        if let Some((topic_type, id)) = self.topics.get_by_topic(&topic) {
-            topic_id = *id;
-            flags.set_topic_id_type(*topic_type as u8);
-        } else {
-            topic_id = self.register(&topic).await?;
-            self.topics.insert(topic, TopicIdType::Id, topic_id)?;
-        }
-        let msg_id = self.msg_id.next();
-        let mut flags = Flags::default();
-        flags.set_topic_id_type(1);
+        let topic = String::<TOPIC>::try_from(topic)?;
+        let subscription = self.topic_filter(&topic, &mut flags);
+        let by_name = matches!(subscription, TopicNameOrId::Name(_));
+        let msg_id = self.shared.acks.next_id()?;
 
         let packet = Message::Subscribe(Subscribe {
             flags,
             msg_id,
-            topic: TopicNameOrId::Id(topic_id),
+            topic: subscription,
         });
         let ack_handler = |msg| {
             match msg {
                 Message::SubAck(SubAck {
-                    code: ReturnCode::Accepted, ..
-                }) => AckResult::Success,
+                    topic_id, msg_id: id, code: ReturnCode::Accepted, ..
+                }) if id == msg_id => AckResult::TopicId(topic_id),
+                Message::SubAck(SubAck {
+                    msg_id: id, code, ..
+                }) if id == msg_id => AckResult::Rejected(code),
+                _ => AckResult::None
+            }
+        };
+
+        match self.send_ack(packet, ack_handler).await {
+            // Wildcard filters get topic id 0, matches are announced with REGISTER
+            Ok(AckResult::TopicId(topic_id)) => {
+                if by_name && topic_id != 0 {
+                    self.shared.topics(|topics| topics.insert(topic.clone(), TopicIdType::Id, topic_id))?;
+                }
+            },
+            result => {
+                self.report(ClientEvent::SubscribeFailed(topic));
+                return Err(result.err().unwrap_or(Error::Rejected));
+            }
+        }
+        if !self.session.subscriptions.contains(&topic) {
+            self.session.subscriptions.push(topic).map_err(|_| Error::SubscriptionFailedInsert)?;
+        }
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        debug!("unsubscribe");
+        let mut flags = Flags::default();
+        let topic = String::<TOPIC>::try_from(topic)?;
+        let unsubscription = self.topic_filter(&topic, &mut flags);
+        let msg_id = self.shared.acks.next_id()?;
+
+        let packet = Message::Unsubscribe(Unsubscribe {
+            flags,
+            msg_id,
+            topic: unsubscription,
+        });
+        let ack_handler = |msg| {
+            match msg {
+                Message::UnsubAck(UnsubAck {
+                    msg_id: id
+                }) if id == msg_id => AckResult::Success,
                 _ => AckResult::None
             }
         };
 
         self.send_ack(packet, ack_handler).await?;
+        self.session.subscriptions.retain(|t| t != &topic);
         Ok(())
     }
 
+    /// Predefined and short topics are referred to by id, anything else by name
+    fn topic_filter(&self, topic: &String<TOPIC>, flags: &mut Flags) -> TopicNameOrId {
+        match self.shared.topics(|topics| topics.get_by_topic(topic)) {
+            Some((topic_type, id)) if topic_type != TopicIdType::Id => {
+                flags.set_topic_id_type(topic_type as u8);
+                TopicNameOrId::Id(id)
+            },
+            // Normal topic ids are not valid in (UN)SUBSCRIBE
+            _ => TopicNameOrId::Name(TopicName::from(topic.as_str()))
+        }
+    }
+
     /// If duration is set, then client will go to sleep, with keep-alive < duration
     pub async fn disconnect(&mut self, duration: Option<u16>) -> Result<(), Error> {
         debug!("disconnect");
@@ -311,43 +1410,277 @@ where
         };
 
         self.send_ack(packet, ack_handler).await?;
+        match duration {
+            Some(_) => {
+                self.session.state = ClientState::Asleep;
+                self.report(ClientEvent::Sleeping);
+            },
+            None => {
+                self.session.state = ClientState::Disconnected;
+                self.report(ClientEvent::Disconnected);
+            }
+        }
         Ok(())
     }
 }
 
+impl<L, const BUF: usize, const TOPIC: usize, const PAYLOAD: usize, const TOPICS: usize, const GATEWAYS: usize> MqttSnSender<'_, L, BUF, TOPIC, PAYLOAD, TOPICS, GATEWAYS>
+where
+    L: AckLink + ConnectTo
+{
+    pub async fn run(
+        &mut self,
+        sleep: u16,
+    ) {
+        loop {
+            match with_timeout(
+                Duration::from_secs(sleep.into()),
+                self.session.rx.next_message_pure()
+            ).await {
+                Ok(msg) if msg.qos == Some(QOS_MINUS_ONE) => {
+                    // QoS -1 does not need a connection
+                    if let Err(e) = self.publish(msg).await {
+                        self.report(ClientEvent::Error(e));
+                    }
+                },
+                Ok(msg) => {
+                    // Handle message received from the user (via DynSubscriber)
+                    self.enqueue(msg);
+                    // Publish aditional msg if queued
+                    while let Some(msg) = self.session.rx.try_next_message_pure() {
+                        self.enqueue(msg);
+                    }
+                    self.send_queued(sleep).await;
+                },
+                // Messages left from earlier attempts or restored from the store
+                _ if !self.session.queue.is_empty() => self.send_queued(sleep).await,
+                _ => {
+                    // Wake up for messages buffered by the gateway
+                    match self.ping().await {
+                        Ok(()) if !self.gateway_expired() => (),
+                        result => {
+                            if let Err(e) = result {
+                                self.report(ClientEvent::Error(e));
+                            }
+                            self.reconnect(sleep).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Connect, publish the queue and go back to sleep. Transient errors are
+    /// retried through a new connection, a fatal error while publishing drops
+    /// the message being sent. Messages left after the retries of CONNECT
+    /// stay queued.
+    async fn send_queued(&mut self, sleep: u16) {
+        for _ in 0..=self.session.retry.get(Request::Connect).retries() {
+            let connected = match self.session.state {
+                ClientState::Active => Ok(()),
+                ClientState::Lost => self.failover(sleep).await,
+                _ => self.connect(sleep).await
+            };
+            // Connection errors say nothing about the queued messages
+            if let Err(e) = connected {
+                self.report(ClientEvent::Error(e));
+                continue;
+            }
+            match self.flush().await {
+                Ok(()) => break,
+                Err(e) => self.report(ClientEvent::Error(e))
+            }
+        }
+        if self.session.state == ClientState::Active {
+            if let Err(e) = self.disconnect(Some(sleep)).await {
+                self.report(ClientEvent::Error(e));
+            }
+        }
+    }
+
+    /// Find a gateway after losing the current one, publish the queue and
+    /// go back to sleep
+    async fn reconnect(&mut self, sleep: u16) {
+        match self.failover(sleep).await {
+            Ok(()) => self.send_queued(sleep).await,
+            Err(e) => self.report(ClientEvent::Error(e))
+        }
+    }
+
+    /// Mark the current gateway lost and connect to the next one
+    async fn failover(&mut self, duration: u16) -> Result<(), Error> {
+        if self.shared.gateways(|gateways| gateways.iter().next().is_none()) {
+            // No alternatives known, try the same gateway again
+            return self.connect(duration).await;
+        }
+        if let Some(gw_id) = self.session.gateway.take() {
+            warn!("gateway {} lost", gw_id);
+            self.shared.gateways(|gateways| gateways.mark_lost(gw_id));
+            self.report(ClientEvent::GatewayLost(gw_id));
+        }
+        self.shared.gateways(|gateways| {
+            if gateways.select().is_none() {
+                gateways.reset_lost();
+            }
+        });
+        while let Some(gw_id) = self.shared.gateways(|gateways| gateways.select().map(|gw| gw.gw_id)) {
+            if self.select_gateway().await.is_ok() && self.connect(duration).await.is_ok() {
+                // The new gateway has no registrations or subscriptions for us
+                if !self.session.clean_session {
+                    self.restore_session().await?;
+                }
+                return Ok(());
+            }
+            self.session.gateway = None;
+            self.shared.gateways(|gateways| gateways.mark_lost(gw_id));
+        }
+        Err(Error::NoGateway)
+    }
+
+    /// Point the socket at the most recently seen gateway
+    pub async fn select_gateway(&mut self) -> Result<u8, Error> {
+        let (gw_id, addr) = self.shared.gateways(|gateways| {
+            gateways.select().map(|gw| (gw.gw_id, gw.addr))
+        }).ok_or(Error::NoGateway)?;
+        debug!("select gateway {}", gw_id);
+        self.link.connect_to(addr).await?;
+        self.session.gateway = Some(gw_id);
+        Ok(gw_id)
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct MqttMessage {
+pub struct MqttMessage<const TOPIC: usize = 256, const PAYLOAD: usize = 256> {
     topic_id: Option<u16>,
-    msg_id: Option<u16>,
+    topic_id_type: Option<TopicIdType>,
+    /// The gateway's msg_id for received messages, ours once sent
+    pub(crate) msg_id: Option<u16>,
+    /// QoS 2 publish acked with PUBREC, only PUBREL is left
+    pub(crate) received: bool,
     qos: Option<u8>,
-    pub topic: String<256>,
-    pub payload: String<256>,
+    pub(crate) request_id: Option<u16>,
+    /// Where the message is kept by an `OutboundStore`
+    slot: Option<u16>,
+    pub topic: String<TOPIC>,
+    /// Raw bytes, see `payload_str` for text. The codec only carries UTF-8
+    /// payloads so far, others fail to publish with `ParseError`.
+    pub payload: Vec<u8, PAYLOAD>,
 }
 
-impl MqttMessage {
+impl<const TOPIC: usize, const PAYLOAD: usize> MqttMessage<TOPIC, PAYLOAD> {
     pub fn new(
         topic: &str,
-        payload: &str,
+        payload: impl AsRef<[u8]>,
         qos: Option<u8>
     ) -> Result<Self, Error> {
         Ok(Self {
             topic_id: None,
+            topic_id_type: None,
             msg_id: None,
+            received: false,
             topic: String::try_from(topic)?,
-            payload: String::try_from(payload)?,
-            qos
+            payload: Vec::from_slice(payload.as_ref())?,
+            qos,
+            request_id: None,
+            slot: None
         })
     }
-    fn from_publish(
+    /// Message for a topic id predefined between client and gateway
+    pub fn new_predefined(
+        topic_id: u16,
+        payload: impl AsRef<[u8]>,
+        qos: Option<u8>
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            topic_id: Some(topic_id),
+            topic_id_type: Some(TopicIdType::PreDef),
+            msg_id: None,
+            received: false,
+            topic: String::new(),
+            payload: Vec::from_slice(payload.as_ref())?,
+            qos,
+            request_id: None,
+            slot: None
+        })
+    }
+    /// Payload as UTF-8 text
+    pub fn payload_str(&self) -> Result<&str, Error> {
+        core::str::from_utf8(&self.payload).map_err(|_| Error::ParseError)
+    }
+    /// Report the outcome of this message under `request_id`, see `Deliveries`
+    pub fn with_request_id(mut self, request_id: u16) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+    fn from_publish<const TOPICS: usize>(
         msg: Publish,
-        topics: &Topics,
+        topics: &Topics<TOPICS, TOPIC>,
     ) -> Result<Self, Error> {
+        let topic_id_type = TopicIdType::try_from(msg.flags.topic_id_type())?;
         Ok(Self {
             topic_id: Some(msg.topic_id),
+            topic_id_type: Some(topic_id_type),
             msg_id: Some(msg.msg_id),
+            received: false,
             qos: Some(msg.flags.qos()),
-            topic: String::try_from(topics.get_by_id(msg.topic_id)?)?,
-            payload: String::try_from(msg.data.as_str())?,
+            request_id: None,
+            slot: None,
+            topic: topics.get_by_id(topic_id_type, msg.topic_id)?,
+            payload: Vec::from_slice(msg.data.as_bytes())?,
+        })
+    }
+    /// Record kept by an `OutboundStore`, `seq` orders records by age. The
+    /// stage and msg_id come first, apart from the rest, and are erased
+    /// flash until the message is sent.
+    pub(crate) fn encode<const R: usize>(&self, seq: u32) -> Result<Vec<u8, R>, Error> {
+        let stage = match (self.msg_id, self.received) {
+            (None, _) => STAGE_NEW,
+            (Some(_), false) => STAGE_SENT,
+            (Some(_), true) => STAGE_RECEIVED,
+        };
+        let present = self.qos.map_or(0, |_| RECORD_QOS)
+            | self.topic_id.map_or(0, |_| RECORD_TOPIC_ID)
+            | self.topic_id_type.map_or(0, |_| RECORD_TOPIC_ID_TYPE)
+            | self.request_id.map_or(0, |_| RECORD_REQUEST_ID);
+        let mut record = Vec::new();
+        for field in [
+            &[stage][..],
+            &self.msg_id.unwrap_or(0xffff).to_be_bytes(),
+            &[0xff; RECORD_PROGRESS - 3],
+            &[present, self.qos.unwrap_or(0), self.topic_id_type.map_or(0, |t| t as u8)],
+            &self.topic_id.unwrap_or(0).to_be_bytes(),
+            &self.request_id.unwrap_or(0).to_be_bytes(),
+            &seq.to_be_bytes(),
+            &(self.topic.len() as u16).to_be_bytes(),
+            self.topic.as_bytes(),
+            &self.payload,
+        ] {
+            record.extend_from_slice(field).map_err(|_| Error::StorageError)?;
+        }
+        Ok(record)
+    }
+    pub(crate) fn decode(record: &[u8]) -> Result<Self, Error> {
+        let header = record.get(..RECORD_HEADER).ok_or(Error::ParseError)?;
+        let stage = header[0];
+        let present = header[RECORD_PROGRESS];
+        let field = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+        let topic_end = RECORD_HEADER + field(RECORD_SEQ + 4) as usize;
+        let topic = record.get(RECORD_HEADER..topic_end).ok_or(Error::ParseError)?;
+        Ok(Self {
+            topic_id: (present & RECORD_TOPIC_ID != 0)
+                .then(|| field(RECORD_PROGRESS + 3)),
+            topic_id_type: match present & RECORD_TOPIC_ID_TYPE {
+                0 => None,
+                _ => Some(TopicIdType::try_from(header[RECORD_PROGRESS + 2])?)
+            },
+            msg_id: (stage != STAGE_NEW).then(|| field(1)),
+            received: stage == STAGE_RECEIVED,
+            qos: (present & RECORD_QOS != 0).then_some(header[RECORD_PROGRESS + 1]),
+            request_id: (present & RECORD_REQUEST_ID != 0)
+                .then(|| field(RECORD_PROGRESS + 5)),
+            slot: None,
+            topic: String::try_from(core::str::from_utf8(topic).map_err(|_| Error::ParseError)?)?,
+            payload: Vec::from_slice(&record[topic_end..])?,
         })
     }
     pub fn get_ack(&self) -> Option<PubAck> {
@@ -361,14 +1694,43 @@ impl MqttMessage {
     }
 }
 
-pub struct MsgId {
-    last_id: u16
+/// Sequence number of a record made by `MqttMessage::encode`
+pub(crate) fn record_seq(record: &[u8]) -> Result<u32, Error> {
+    match record.get(RECORD_SEQ..RECORD_SEQ + 4) {
+        Some(&[a, b, c, d]) => Ok(u32::from_be_bytes([a, b, c, d])),
+        _ => Err(Error::ParseError)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Will<const TOPIC: usize = 256, const PAYLOAD: usize = 256> {
+    pub topic: String<TOPIC>,
+    pub message: String<PAYLOAD>,
+    pub qos: u8,
+    pub retain: bool,
 }
 
-impl MsgId {
-    fn next(&mut self) -> u16 {
-        self.last_id = self.last_id.wrapping_add(1);
-        self.last_id
+impl<const TOPIC: usize, const PAYLOAD: usize> Will<TOPIC, PAYLOAD> {
+    pub fn new(
+        topic: &str,
+        message: &str,
+        qos: u8,
+        retain: bool
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            topic: String::try_from(topic)?,
+            message: String::try_from(message)?,
+            qos, retain
+        })
+    }
+    fn flags(&self) -> Flags {
+        let mut flags = Flags::default();
+        flags.set_qos(self.qos);
+        flags.set_retain(self.retain);
+        flags
+    }
+    fn data(&self) -> Result<PublishData, Error> {
+        Ok(PublishData::try_from(self.message.as_str())?)
     }
 }
 
@@ -383,7 +1745,60 @@ pub enum MqttSnClientError {
     ParseError,
     TopicNotRegistered,
     TopicFailedInsert,
+    SubscriptionFailedInsert,
+    GatewayFailedInsert,
+    NoGateway,
     NoPingResponse,
+    NoWill,
+    Rejected,
+    Congestion,
+    InvalidTopicId,
+    NotSupported,
+    DeliveriesFull,
+    InboundFull,
+    StoreFull,
+    StorageError,
+    InvalidWindow,
+}
+
+impl MqttSnClientError {
+    /// Transient errors may succeed on retry or through another gateway.
+    /// Congestion is backed off from by `publish`, once reported the
+    /// message is given up on.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::SocketError
+            | Error::AckError
+            | Error::NoGateway
+            | Error::NoPingResponse
+            | Error::InboundFull
+        )
+    }
+    pub fn rejection(&self) -> Option<Rejection> {
+        match self {
+            Error::Congestion => Some(Rejection::Congestion),
+            Error::InvalidTopicId => Some(Rejection::InvalidTopicId),
+            Error::NotSupported => Some(Rejection::NotSupported),
+            _ => None
+        }
+    }
+}
+
+impl From<ReturnCode> for MqttSnClientError {
+    fn from(code: ReturnCode) -> Self {
+        match code {
+            ReturnCode::Rejected(RejectedReason::Congestion) => Error::Congestion,
+            ReturnCode::Rejected(RejectedReason::InvalidTopicId) => Error::InvalidTopicId,
+            _ => Error::NotSupported
+        }
+    }
+}
+
+impl From<AckMapError> for MqttSnClientError {
+    fn from(_e: AckMapError) -> Self {
+        MqttSnClientError::AckError
+    }
 }
 
 impl From<SocketError> for MqttSnClientError {
@@ -0,0 +1,198 @@
+use mqtt_sn::defs::*;
+use embassy_time::{Duration, Instant};
+
+/// Requests the gateway answers with an ack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Request {
+    Connect,
+    Register,
+    Publish,
+    Release,
+    Subscribe,
+    Unsubscribe,
+    Will,
+    Ping,
+    Disconnect,
+}
+
+const N_REQUESTS: usize = 9;
+
+impl Request {
+    pub fn of(packet: &Message) -> Self {
+        match packet {
+            Message::Register(_) => Request::Register,
+            Message::Publish(_) => Request::Publish,
+            Message::PubRel(_) => Request::Release,
+            Message::Subscribe(_) => Request::Subscribe,
+            Message::Unsubscribe(_) => Request::Unsubscribe,
+            Message::WillTopicUpd(_) | Message::WillMsgUpd(_) => Request::Will,
+            Message::PingReq(_) => Request::Ping,
+            Message::Disconnect(_) => Request::Disconnect,
+            // CONNECT and the will sent while connecting
+            _ => Request::Connect,
+        }
+    }
+}
+
+/// Timeouts and retransmissions of a request
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    timeout: Duration,
+    retries: u8,
+    backoff: u32,
+    max_timeout: Duration,
+    jitter: u8,
+}
+
+impl Retry {
+    /// Fixed timeout, the gateway is considered lost after `retries`
+    /// retransmissions
+    pub const fn new(timeout: Duration, retries: u8) -> Self {
+        Self {
+            timeout,
+            retries,
+            backoff: 1,
+            max_timeout: timeout,
+            jitter: 0,
+        }
+    }
+
+    /// Multiply the timeout by `factor` on every retransmission, up to
+    /// `max_timeout`. A factor of 0 or 1 keeps the timeout fixed.
+    pub const fn with_backoff(mut self, factor: u32, max_timeout: Duration) -> Self {
+        self.backoff = factor;
+        self.max_timeout = max_timeout;
+        self
+    }
+
+    /// Add up to `percent` of the timeout at random, so clients that lost
+    /// the gateway together do not retransmit together
+    pub const fn with_jitter(mut self, percent: u8) -> Self {
+        self.jitter = percent;
+        self
+    }
+
+    pub fn retries(&self) -> u8 {
+        self.retries
+    }
+
+    /// Timeout of the first transmission (0) and each retransmission
+    pub fn timeout(&self, attempt: u8) -> Duration {
+        let mut timeout = self.timeout;
+        for _ in 0..attempt {
+            if timeout >= self.max_timeout {
+                break;
+            }
+            timeout = (timeout * self.backoff.max(1)).min(self.max_timeout);
+        }
+        let jitter = timeout.as_ticks() * self.jitter as u64 / 100;
+        timeout + Duration::from_ticks(random() % (jitter + 1))
+    }
+}
+
+/// Retry of each request, one for all unless overridden
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    default: Retry,
+    overrides: [Option<Retry>; N_REQUESTS],
+}
+
+impl RetryPolicy {
+    pub const fn new(default: Retry) -> Self {
+        Self {
+            default,
+            overrides: [None; N_REQUESTS],
+        }
+    }
+
+    pub const fn with_override(mut self, request: Request, retry: Retry) -> Self {
+        self.overrides[request as usize] = Some(retry);
+        self
+    }
+
+    pub fn get(&self, request: Request) -> Retry {
+        self.overrides[request as usize].unwrap_or(self.default)
+    }
+
+    /// Retry of requests without an override, also for waits that are not
+    /// requests
+    pub fn default_retry(&self) -> Retry {
+        self.default
+    }
+}
+
+/// Good enough for jitter, there is no RNG to rely on
+fn random() -> u64 {
+    let mut x = Instant::now().as_ticks() ^ 0x9E37_79B9_7F4A_7C15;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts<const N: usize>(retry: &Retry) -> [Duration; N] {
+        core::array::from_fn(|attempt| retry.timeout(attempt as u8))
+    }
+
+    #[test]
+    fn backoff_up_to_max_timeout() {
+        let retry = Retry::new(Duration::from_secs(5), 5)
+            .with_backoff(2, Duration::from_secs(30));
+        assert_eq!(timeouts::<6>(&retry), [5, 10, 20, 30, 30, 30].map(Duration::from_secs));
+    }
+
+    #[test]
+    fn fixed_timeout() {
+        let fixed = [Duration::from_secs(10); 4];
+        let retry = Retry::new(Duration::from_secs(10), 3);
+        assert_eq!(timeouts::<4>(&retry), fixed);
+        // No backoff instead of a zero timeout
+        let retry = retry.with_backoff(0, Duration::from_secs(60));
+        assert_eq!(timeouts::<4>(&retry), fixed);
+        // The first timeout is kept when the cap is below it
+        let retry = retry.with_backoff(2, Duration::from_secs(5));
+        assert_eq!(timeouts::<4>(&retry), fixed);
+    }
+
+    #[test]
+    fn jitter_within_percent() {
+        let retry = Retry::new(Duration::from_secs(10), 3)
+            .with_backoff(2, Duration::from_secs(25))
+            .with_jitter(10);
+        for _ in 0..100 {
+            for (attempt, base) in [(0, 10), (1, 20), (2, 25), (3, 25)] {
+                let base = Duration::from_secs(base);
+                let timeout = retry.timeout(attempt);
+                assert!(timeout >= base && timeout <= base + base / 10);
+            }
+        }
+    }
+
+    #[test]
+    fn overrides_per_request() {
+        let policy = RetryPolicy::new(Retry::new(Duration::from_secs(10), 10))
+            .with_override(Request::Ping, Retry::new(Duration::from_secs(2), 1));
+        assert_eq!(policy.get(Request::Ping).retries(), 1);
+        assert_eq!(policy.default_retry().retries(), 10);
+        assert_eq!(policy.get(Request::Ping).timeout(0), Duration::from_secs(2));
+        for request in [Request::Connect, Request::Publish, Request::Disconnect] {
+            assert_eq!(policy.get(request).retries(), 10);
+            assert_eq!(policy.get(request).timeout(0), Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn request_of_packet() {
+        let ping = Message::PingReq(PingReq { client_id: "".into() });
+        assert_eq!(Request::of(&ping), Request::Ping);
+        let release = Message::PubRel(PubRel { msg_id: 1 });
+        assert_eq!(Request::of(&release), Request::Release);
+        let will = Message::WillMsg(WillMsg { msg: PublishData::new() });
+        assert_eq!(Request::of(&will), Request::Connect);
+    }
+}
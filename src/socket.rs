@@ -1,9 +1,16 @@
 #[cfg(feature = "std")]
 use tokio::net::UdpSocket;
+use no_std_net::SocketAddr;
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{self, raw::CriticalSectionRawMutex};
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_sync::signal::Signal;
+use embassy_futures::select::{select, Either};
 
 #[derive(Debug)]
 pub enum SocketError {
     Generic,
+    Unsupported,
 }
 
 #[cfg(feature = "std")]
@@ -21,6 +28,146 @@ pub trait ReceiveBytes {
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError>;
 }
 
+/// Unconnected sockets, used for gateway discovery
+pub trait SendBytesTo {
+    async fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> Result<(), SocketError>;
+}
+
+pub trait ReceiveBytesFrom {
+    async fn recv_from<'a>(&mut self, buf: &'a mut [u8]) -> Result<(&'a mut [u8], SocketAddr), SocketError>;
+}
+
+/// Sockets that can be pointed at another gateway
+pub trait ConnectTo {
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError>;
+}
+
+/// Sockets that can be used from two tasks at once,
+/// the receiving half also sends replies
+pub trait Split {
+    type Receiver<'a>: SendBytes + ReceiveBytes where Self: 'a;
+    type Sender<'a>: SendBytes where Self: 'a;
+    fn split(&mut self) -> (Self::Receiver<'_>, Self::Sender<'_>);
+}
+
+/// Splits sockets that can only be used by one task at a time, e.g. DTLS
+/// sessions. A send cancels the receive in progress, which is started
+/// again once the socket is free, so receiving must be cancel safe.
+pub struct SharedSocket<T> {
+    socket: Mutex<CriticalSectionRawMutex, T>,
+    /// Sends waiting for the socket
+    waiting: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<u8>>,
+    wake: Signal<CriticalSectionRawMutex, ()>,
+    /// Raised once no send waits anymore
+    sent: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl<T> SharedSocket<T> {
+    pub fn new(socket: T) -> Self {
+        Self {
+            socket: Mutex::new(socket),
+            waiting: blocking_mutex::Mutex::new(Cell::new(0)),
+            wake: Signal::new(),
+            sent: Signal::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.socket.get_mut()
+    }
+
+    /// Take the socket over from a receive in progress
+    async fn lock(&self) -> MutexGuard<'_, CriticalSectionRawMutex, T> {
+        let _waiting = Waiting::new(self);
+        self.wake.signal(());
+        self.socket.lock().await
+    }
+
+    fn waiting(&self) -> bool {
+        self.waiting.lock(|waiting| waiting.get() > 0)
+    }
+}
+
+/// Counts a send waiting for a `SharedSocket`, also if it is cancelled
+struct Waiting<'a, T>(&'a SharedSocket<T>);
+
+impl<'a, T> Waiting<'a, T> {
+    fn new(socket: &'a SharedSocket<T>) -> Self {
+        socket.waiting.lock(|waiting| waiting.set(waiting.get() + 1));
+        Self(socket)
+    }
+}
+
+impl<T> Drop for Waiting<'_, T> {
+    fn drop(&mut self) {
+        let last = self.0.waiting.lock(|waiting| {
+            waiting.set(waiting.get() - 1);
+            waiting.get() == 0
+        });
+        if last {
+            self.0.sent.signal(());
+        }
+    }
+}
+
+impl<T: SendBytes> SendBytes for SharedSocket<T> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.get_mut().send(buf).await
+    }
+}
+
+impl<T: ReceiveBytes> ReceiveBytes for SharedSocket<T> {
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
+        self.get_mut().recv(buf).await
+    }
+}
+
+impl<T: ConnectTo> ConnectTo for SharedSocket<T> {
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.get_mut().connect_to(addr).await
+    }
+}
+
+impl<T: SendBytes + ReceiveBytes> Split for SharedSocket<T> {
+    type Receiver<'a> = SharedHalf<'a, T> where T: 'a;
+    type Sender<'a> = SharedHalf<'a, T> where T: 'a;
+    fn split(&mut self) -> (SharedHalf<'_, T>, SharedHalf<'_, T>) {
+        (SharedHalf(self), SharedHalf(self))
+    }
+}
+
+/// Half of a split `SharedSocket`
+pub struct SharedHalf<'a, T>(&'a SharedSocket<T>);
+
+impl<T: SendBytes> SendBytes for SharedHalf<'_, T> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.0.lock().await.send(buf).await
+    }
+}
+
+impl<T: ReceiveBytes> ReceiveBytes for SharedHalf<'_, T> {
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
+        loop {
+            // Waiting sends go first
+            if self.0.waiting() {
+                self.0.sent.wait().await;
+                continue;
+            }
+            let mut socket = self.0.socket.lock().await;
+            if let Either::First(result) = select(socket.recv(buf), self.0.wake.wait()).await {
+                let len = result?.len();
+                return Ok(&mut buf[..len]);
+            }
+        }
+    }
+}
+
+impl<T: ConnectTo> ConnectTo for SharedHalf<'_, T> {
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.0.lock().await.connect_to(addr).await
+    }
+}
+
 
 #[cfg(feature = "std")]
 pub struct TokioUdp(pub UdpSocket);
@@ -39,3 +186,63 @@ impl ReceiveBytes for TokioUdp {
         Ok(self.0.recv(buf).await.map(|len| &mut buf[..len])?)
     }
 }
+
+#[cfg(feature = "std")]
+impl SendBytesTo for TokioUdp {
+    async fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> Result<(), SocketError> {
+        self.0.send_to(buf, addr).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl ReceiveBytesFrom for TokioUdp {
+    async fn recv_from<'a>(&mut self, buf: &'a mut [u8]) -> Result<(&'a mut [u8], SocketAddr), SocketError> {
+        let (len, addr) = self.0.recv_from(buf).await?;
+        Ok((&mut buf[..len], addr))
+    }
+}
+
+#[cfg(feature = "std")]
+impl ConnectTo for TokioUdp {
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.0.connect(addr).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Split for TokioUdp {
+    type Receiver<'a> = TokioUdpRef<'a>;
+    type Sender<'a> = TokioUdpRef<'a>;
+    fn split(&mut self) -> (TokioUdpRef<'_>, TokioUdpRef<'_>) {
+        (TokioUdpRef(&self.0), TokioUdpRef(&self.0))
+    }
+}
+
+/// Half of a split `TokioUdp`
+#[cfg(feature = "std")]
+pub struct TokioUdpRef<'a>(&'a UdpSocket);
+
+#[cfg(feature = "std")]
+impl SendBytes for TokioUdpRef<'_> {
+    async fn send(&mut self, buf: &[u8]) -> Result<(), SocketError> {
+        self.0.send(buf).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl ReceiveBytes for TokioUdpRef<'_> {
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SocketError> {
+        Ok(self.0.recv(buf).await.map(|len| &mut buf[..len])?)
+    }
+}
+
+#[cfg(feature = "std")]
+impl ConnectTo for TokioUdpRef<'_> {
+    async fn connect_to(&mut self, addr: SocketAddr) -> Result<(), SocketError> {
+        self.0.connect(addr).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,666 @@
+use heapless::Vec;
+use embedded_storage::nor_flash::NorFlash;
+use crate::mqttsn::{record_seq, MqttMessage, MqttSnClientError};
+#[cfg(feature = "std")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{Read, Seek, SeekFrom, Write};
+
+type Error = MqttSnClientError;
+
+/// Bytes of a record besides topic and payload
+pub const RECORD_HEADER: usize = 21;
+/// Bytes at the start of a record holding the progress of the handshake
+pub const RECORD_PROGRESS: usize = 8;
+
+/// Keeps QoS 1 and 2 messages until acked, also across reboots with a
+/// persistent backend
+pub trait OutboundStore<const TOPIC: usize, const PAYLOAD: usize> {
+    /// Store a message, returns the slot it is kept in
+    fn store(&mut self, msg: &MqttMessage<TOPIC, PAYLOAD>) -> Result<u16, Error>;
+    /// Replace a stored message, e.g. once its handshake progressed
+    fn update(&mut self, slot: u16, msg: &MqttMessage<TOPIC, PAYLOAD>) -> Result<(), Error>;
+    fn remove(&mut self, slot: u16) -> Result<(), Error>;
+    /// Message kept in a slot, e.g. since before a reboot. Request ids are
+    /// handed out again after a reboot, messages stored before it are
+    /// loaded without theirs.
+    fn load(&mut self, slot: u16) -> Result<Option<MqttMessage<TOPIC, PAYLOAD>>, Error>;
+    /// Used slot stored first, leaving out those `skip` is true for
+    fn oldest(&self, skip: &dyn Fn(u16) -> bool) -> Option<u16>;
+    fn slots(&self) -> u16;
+}
+
+/// Records of at most R bytes in numbered slots
+pub trait StoreBackend {
+    fn write(&mut self, slot: u16, record: &[u8]) -> Result<(), Error>;
+    /// None for an empty slot
+    fn read<'a>(&mut self, slot: u16, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Error>;
+    fn erase(&mut self, slot: u16) -> Result<(), Error>;
+}
+
+/// Up to N messages in records of R bytes, R must fit the longest topic
+/// and payload plus RECORD_HEADER
+pub struct Store<B, const N: usize, const R: usize> {
+    backend: B,
+    used: [bool; N],
+    /// Stored before the store was opened
+    restored: [bool; N],
+    /// Order of the messages in used slots
+    seq: [u32; N],
+    next_seq: u32,
+    buffer: [u8; R],
+}
+
+impl<B: StoreBackend, const N: usize, const R: usize> Store<B, N, R> {
+    /// Slots already used in the backend are kept, unreadable ones too so
+    /// `load` reports them, as the oldest
+    pub fn new(mut backend: B) -> Result<Self, Error> {
+        let mut used = [false; N];
+        let mut seq = [0u32; N];
+        let mut buffer = [0u8; R];
+        for slot in 0..N {
+            match backend.read(slot as u16, &mut buffer) {
+                Ok(None) => (),
+                Ok(Some(record)) => {
+                    used[slot] = true;
+                    seq[slot] = record_seq(record).unwrap_or(0);
+                },
+                Err(_) => used[slot] = true
+            }
+        }
+        let next_seq = seq.iter().max().map_or(0, |seq| seq + 1);
+        Ok(Self { backend, used, restored: used, seq, next_seq, buffer })
+    }
+
+    fn check(&self, slot: u16) -> Result<usize, Error> {
+        match self.used.get(slot as usize) {
+            Some(_) => Ok(slot as usize),
+            None => Err(Error::StorageError)
+        }
+    }
+}
+
+impl<B, const N: usize, const R: usize, const TOPIC: usize, const PAYLOAD: usize>
+    OutboundStore<TOPIC, PAYLOAD> for Store<B, N, R>
+where
+    B: StoreBackend
+{
+    fn store(&mut self, msg: &MqttMessage<TOPIC, PAYLOAD>) -> Result<u16, Error> {
+        let slot = self.used.iter().position(|used| !used).ok_or(Error::StoreFull)?;
+        let record: Vec<u8, R> = msg.encode(self.next_seq)?;
+        self.backend.write(slot as u16, &record)?;
+        self.used[slot] = true;
+        self.restored[slot] = false;
+        self.seq[slot] = self.next_seq;
+        self.next_seq += 1;
+        Ok(slot as u16)
+    }
+
+    fn update(&mut self, slot: u16, msg: &MqttMessage<TOPIC, PAYLOAD>) -> Result<(), Error> {
+        let index = self.check(slot)?;
+        if !self.used[index] {
+            return Err(Error::StorageError);
+        }
+        let record: Vec<u8, R> = msg.encode(self.seq[index])?;
+        self.backend.write(slot, &record)
+    }
+
+    fn remove(&mut self, slot: u16) -> Result<(), Error> {
+        let index = self.check(slot)?;
+        self.backend.erase(slot)?;
+        self.used[index] = false;
+        Ok(())
+    }
+
+    fn load(&mut self, slot: u16) -> Result<Option<MqttMessage<TOPIC, PAYLOAD>>, Error> {
+        if !self.used[self.check(slot)?] {
+            return Ok(None);
+        }
+        match self.backend.read(slot, &mut self.buffer)? {
+            Some(record) => {
+                let mut msg = MqttMessage::decode(record)?;
+                if self.restored[slot as usize] {
+                    msg.request_id = None;
+                }
+                Ok(Some(msg))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn oldest(&self, skip: &dyn Fn(u16) -> bool) -> Option<u16> {
+        (0..N)
+            .filter(|slot| self.used[*slot] && !skip(*slot as u16))
+            .min_by_key(|slot| self.seq[*slot])
+            .map(|slot| slot as u16)
+    }
+
+    fn slots(&self) -> u16 {
+        N as u16
+    }
+}
+
+/// Lost on reboot, for devices without persistent storage
+pub struct RamBackend<const N: usize, const R: usize> {
+    records: [Option<Vec<u8, R>>; N],
+}
+
+impl<const N: usize, const R: usize> RamBackend<N, R> {
+    pub fn new() -> Self {
+        Self { records: core::array::from_fn(|_| None) }
+    }
+}
+
+impl<const N: usize, const R: usize> Default for RamBackend<N, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, const R: usize> StoreBackend for RamBackend<N, R> {
+    fn write(&mut self, slot: u16, record: &[u8]) -> Result<(), Error> {
+        let entry = self.records.get_mut(slot as usize).ok_or(Error::StoreFull)?;
+        *entry = Some(Vec::from_slice(record).map_err(|_| Error::StorageError)?);
+        Ok(())
+    }
+
+    fn read<'a>(&mut self, slot: u16, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Error> {
+        match self.records.get(slot as usize) {
+            Some(Some(record)) => {
+                let buf = buf.get_mut(..record.len()).ok_or(Error::StorageError)?;
+                buf.copy_from_slice(record);
+                Ok(Some(buf))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    fn erase(&mut self, slot: u16) -> Result<(), Error> {
+        if let Some(entry) = self.records.get_mut(slot as usize) {
+            *entry = None;
+        }
+        Ok(())
+    }
+}
+
+/// Length of a record, 0 or erased flash for an empty slot
+const RECORD_LEN: usize = 2;
+
+/// Slots of R bytes in a file
+#[cfg(feature = "std")]
+pub struct FileBackend<const R: usize> {
+    file: File,
+}
+
+#[cfg(feature = "std")]
+impl<const R: usize> FileBackend<R> {
+    pub fn new(path: impl AsRef<std::path::Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|_| Error::StorageError)?;
+        Ok(Self { file })
+    }
+
+    fn seek(&mut self, slot: u16) -> std::io::Result<u64> {
+        self.file.seek(SeekFrom::Start(slot as u64 * (RECORD_LEN + R) as u64))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<const R: usize> StoreBackend for FileBackend<R> {
+    fn write(&mut self, slot: u16, record: &[u8]) -> Result<(), Error> {
+        if record.len() > R {
+            return Err(Error::StorageError);
+        }
+        let address = self.seek(slot).map_err(|_| Error::StorageError)?;
+        // Length last, the slot is only used once the record is complete
+        self.file.seek(SeekFrom::Start(address + RECORD_LEN as u64))
+            .and_then(|_| self.file.write_all(record))
+            .and_then(|_| self.file.sync_data())
+            .and_then(|_| self.seek(slot))
+            .and_then(|_| self.file.write_all(&(record.len() as u16).to_le_bytes()))
+            .and_then(|_| self.file.sync_data())
+            .map_err(|_| Error::StorageError)
+    }
+
+    fn read<'a>(&mut self, slot: u16, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Error> {
+        let mut len = [0u8; RECORD_LEN];
+        self.seek(slot).map_err(|_| Error::StorageError)?;
+        match self.file.read_exact(&mut len) {
+            Ok(()) => (),
+            // Past the end of the file
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(_) => return Err(Error::StorageError)
+        }
+        match u16::from_le_bytes(len) as usize {
+            0 => Ok(None),
+            len => {
+                let buf = buf.get_mut(..len).ok_or(Error::StorageError)?;
+                self.file.read_exact(buf).map_err(|_| Error::StorageError)?;
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    fn erase(&mut self, slot: u16) -> Result<(), Error> {
+        self.seek(slot)
+            .and_then(|_| self.file.write_all(&[0u8; RECORD_LEN]))
+            .and_then(|_| self.file.sync_data())
+            .map_err(|_| Error::StorageError)
+    }
+}
+
+/// Largest write size of flash a FlashBackend can use, a record starts with
+/// its progress in whole writes
+const MAX_WRITE: usize = RECORD_PROGRESS;
+
+/// Slots of whole sectors of NOR flash from `offset` on, which must be
+/// sector aligned. Storing a message erases the sectors of its slot only, a
+/// record advancing the handshake of the stored one and removal clear bits
+/// in place.
+pub struct FlashBackend<S, const R: usize> {
+    storage: S,
+    offset: u32,
+}
+
+impl<S: NorFlash, const R: usize> FlashBackend<S, R> {
+    /// Flash a record cannot be written to a word at a time
+    const CHECK: () = {
+        assert!(S::WRITE_SIZE <= MAX_WRITE && MAX_WRITE.is_multiple_of(S::WRITE_SIZE), "write size must divide 8");
+        assert!(S::WRITE_SIZE.is_multiple_of(S::READ_SIZE), "read size must divide write size");
+    };
+    /// The length of a record in a write of its own
+    const HEADER: usize = if S::WRITE_SIZE > RECORD_LEN { S::WRITE_SIZE } else { RECORD_LEN };
+    const SLOT: usize = (Self::HEADER + R).div_ceil(S::ERASE_SIZE) * S::ERASE_SIZE;
+
+    pub fn new(storage: S, offset: u32) -> Self {
+        let () = Self::CHECK;
+        Self { storage, offset }
+    }
+
+    fn address(&self, slot: u16) -> u32 {
+        self.offset + slot as u32 * Self::SLOT as u32
+    }
+
+    /// Writes of `bytes`, the last padded with erased flash
+    fn words(bytes: &[u8]) -> impl Iterator<Item = (u32, [u8; MAX_WRITE])> + '_ {
+        bytes.chunks(S::WRITE_SIZE).enumerate().map(|(i, chunk)| {
+            let mut word = [0xff; MAX_WRITE];
+            word[..chunk.len()].copy_from_slice(chunk);
+            ((i * S::WRITE_SIZE) as u32, word)
+        })
+    }
+
+    /// True if writing `bytes` at `address` only clears bits
+    fn writable(&mut self, address: u32, bytes: &[u8]) -> Result<bool, Error> {
+        let mut old = [0u8; MAX_WRITE];
+        for (at, word) in Self::words(bytes) {
+            let old = &mut old[..S::WRITE_SIZE];
+            self.storage.read(address + at, old).map_err(|_| Error::StorageError)?;
+            if old.iter().zip(&word).any(|(old, new)| old & new != *new) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Write `bytes` at `address`, skipping writes flash already holds
+    fn program(&mut self, address: u32, bytes: &[u8]) -> Result<(), Error> {
+        let mut old = [0u8; MAX_WRITE];
+        for (at, word) in Self::words(bytes) {
+            let (old, word) = (&mut old[..S::WRITE_SIZE], &word[..S::WRITE_SIZE]);
+            self.storage.read(address + at, old).map_err(|_| Error::StorageError)?;
+            if old != word {
+                self.storage.write(address + at, word).map_err(|_| Error::StorageError)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read `buf` from `address` in whole reads
+    fn fetch(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Error> {
+        let aligned = buf.len() - buf.len() % S::READ_SIZE;
+        self.storage.read(address, &mut buf[..aligned]).map_err(|_| Error::StorageError)?;
+        if aligned < buf.len() {
+            let mut word = [0u8; MAX_WRITE];
+            self.storage.read(address + aligned as u32, &mut word[..S::READ_SIZE])
+                .map_err(|_| Error::StorageError)?;
+            let rest = buf.len() - aligned;
+            buf[aligned..].copy_from_slice(&word[..rest]);
+        }
+        Ok(())
+    }
+
+    /// Length of the record in a slot, None if it is empty
+    fn length(&mut self, address: u32) -> Result<Option<usize>, Error> {
+        let mut len = [0u8; MAX_WRITE];
+        self.storage.read(address, &mut len[..Self::HEADER]).map_err(|_| Error::StorageError)?;
+        match u16::from_le_bytes([len[0], len[1]]) {
+            0 | 0xffff => Ok(None),
+            len => Ok(Some(len as usize))
+        }
+    }
+}
+
+impl<S: NorFlash, const R: usize> StoreBackend for FlashBackend<S, R> {
+    fn write(&mut self, slot: u16, record: &[u8]) -> Result<(), Error> {
+        if record.len() > R {
+            return Err(Error::StorageError);
+        }
+        let address = self.address(slot);
+        let data = address + Self::HEADER as u32;
+        // Only the progress of a record changes in place, anything else
+        // needs the slot erased
+        if self.length(address)? != Some(record.len()) || !self.writable(data, record)? {
+            self.storage.erase(address, address + Self::SLOT as u32).map_err(|_| Error::StorageError)?;
+        }
+        self.program(data, record)?;
+        // Length last, the slot is only used once the record is complete
+        self.program(address, &(record.len() as u16).to_le_bytes())
+    }
+
+    fn read<'a>(&mut self, slot: u16, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Error> {
+        let address = self.address(slot);
+        match self.length(address)? {
+            None => Ok(None),
+            Some(len) if len > R => Err(Error::StorageError),
+            Some(len) => {
+                let buf = buf.get_mut(..len).ok_or(Error::StorageError)?;
+                self.fetch(address + Self::HEADER as u32, buf)?;
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    fn erase(&mut self, slot: u16) -> Result<(), Error> {
+        let address = self.address(slot);
+        self.program(address, &[0u8; RECORD_LEN])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    const TOPIC: usize = 32;
+    const PAYLOAD: usize = 32;
+    const R: usize = TOPIC + PAYLOAD + RECORD_HEADER;
+
+    type Message = MqttMessage<TOPIC, PAYLOAD>;
+
+    /// NOR flash that only clears bits on write, and a word at most twice
+    /// between erases
+    struct NorMock {
+        memory: [u8; 2048],
+        writes: [u8; 2048 / 4],
+        erases: usize,
+    }
+
+    impl NorMock {
+        fn new() -> Self {
+            Self { memory: [0xff; 2048], writes: [0; 2048 / 4], erases: 0 }
+        }
+    }
+
+    impl ErrorType for NorMock {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for NorMock {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let memory = self.memory.get(offset..offset + bytes.len())
+                .ok_or(NorFlashErrorKind::OutOfBounds)?;
+            bytes.copy_from_slice(memory);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.memory.len()
+        }
+    }
+
+    impl NorFlash for NorMock {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = 256;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            let (from, to) = (from as usize, to as usize);
+            assert!(from % Self::ERASE_SIZE == 0 && to % Self::ERASE_SIZE == 0);
+            self.memory[from..to].fill(0xff);
+            self.writes[from / Self::WRITE_SIZE..to / Self::WRITE_SIZE].fill(0);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            assert!(offset % Self::WRITE_SIZE == 0 && bytes.len() % Self::WRITE_SIZE == 0);
+            for (m, x) in self.memory[offset..].iter_mut().zip(bytes) {
+                assert_eq!(*m & x, *x, "write without erase");
+                *m = *x;
+            }
+            for writes in &mut self.writes[offset / Self::WRITE_SIZE..(offset + bytes.len()) / Self::WRITE_SIZE] {
+                *writes += 1;
+                assert!(*writes <= 2, "word written too often");
+            }
+            Ok(())
+        }
+    }
+
+    fn messages() -> [Message; 3] {
+        [
+            Message::new("test/a", b"hello", Some(1)).unwrap().with_request_id(7),
+            Message::new("test/b", [0u8, 0xff, 1], Some(2)).unwrap(),
+            Message::new_predefined(42, b"", Some(1)).unwrap().with_request_id(8),
+        ]
+    }
+
+    fn record(msg: &Message) -> Vec<u8, R> {
+        msg.encode(0).unwrap()
+    }
+
+    fn load<B: StoreBackend>(store: &mut Store<B, 4, R>, slot: u16) -> Result<Option<Message>, Error> {
+        store.load(slot)
+    }
+
+    fn remove<B: StoreBackend>(store: &mut Store<B, 4, R>, slot: u16) {
+        OutboundStore::<TOPIC, PAYLOAD>::remove(store, slot).unwrap()
+    }
+
+    /// Store, load and remove messages, removed slots are used again
+    fn round_trip<B: StoreBackend>(backend: B) {
+        let mut store = Store::<B, 4, R>::new(backend).unwrap();
+        let messages = messages();
+        for (slot, msg) in messages.iter().enumerate() {
+            assert_eq!(store.store(msg).unwrap(), slot as u16);
+        }
+        for (slot, msg) in messages.iter().enumerate() {
+            assert_eq!(record(&load(&mut store, slot as u16).unwrap().unwrap()), record(msg));
+        }
+        assert!(load(&mut store, 3).unwrap().is_none());
+        assert!(matches!(load(&mut store, 4), Err(Error::StorageError)));
+        assert!(matches!(OutboundStore::<TOPIC, PAYLOAD>::remove(&mut store, 4), Err(Error::StorageError)));
+        remove(&mut store, 1);
+        assert!(load(&mut store, 1).unwrap().is_none());
+        assert_eq!(store.store(&messages[1]).unwrap(), 1);
+        store.store(&messages[1]).unwrap();
+        assert!(matches!(store.store(&messages[1]), Err(Error::StoreFull)));
+        // Slots 0 and 1 are left for `reopen`
+        remove(&mut store, 2);
+        remove(&mut store, 3);
+    }
+
+    /// Slots from the oldest message to the newest
+    fn order<B: StoreBackend>(store: &Store<B, 4, R>) -> Vec<u16, 4> {
+        let mut order: Vec<u16, 4> = Vec::new();
+        while let Some(slot) = OutboundStore::<TOPIC, PAYLOAD>::oldest(store, &|slot| order.contains(&slot)) {
+            order.push(slot).unwrap();
+        }
+        order
+    }
+
+    /// Slots are used again out of order, messages still come oldest first
+    /// and keep the progress of their handshake
+    fn progress_and_order<B: StoreBackend>(backend: B) -> Store<B, 4, R> {
+        let mut store = Store::<B, 4, R>::new(backend).unwrap();
+        let messages = messages();
+        for msg in &messages {
+            store.store(msg).unwrap();
+        }
+        remove(&mut store, 0);
+        assert_eq!(store.store(&messages[0]).unwrap(), 0);
+        assert_eq!(order(&store), [1, 2, 0]);
+        let mut msg = load(&mut store, 1).unwrap().unwrap();
+        assert_eq!((msg.msg_id, msg.received), (None, false));
+        msg.msg_id = Some(0x1234);
+        store.update(1, &msg).unwrap();
+        let loaded = load(&mut store, 1).unwrap().unwrap();
+        assert_eq!((loaded.msg_id, loaded.received), (Some(0x1234), false));
+        msg.received = true;
+        store.update(1, &msg).unwrap();
+        let loaded = load(&mut store, 1).unwrap().unwrap();
+        assert_eq!((loaded.msg_id, loaded.received), (Some(0x1234), true));
+        assert!(matches!(store.update(3, &msg), Err(Error::StorageError)));
+        assert_eq!(order(&store), [1, 2, 0]);
+        store
+    }
+
+    /// Messages stored before reopening are loaded without request id
+    fn reopen<B: StoreBackend>(backend: B) -> Store<B, 4, R> {
+        let mut store = Store::<B, 4, R>::new(backend).unwrap();
+        let messages = messages();
+        assert!(load(&mut store, 0).unwrap().is_some());
+        assert!(load(&mut store, 1).unwrap().is_some());
+        let restored = load(&mut store, 0).unwrap().unwrap();
+        assert_eq!(restored.request_id, None);
+        assert_eq!(record(&restored.with_request_id(7)), record(&messages[0]));
+        assert_eq!(store.store(&messages[2]).unwrap(), 2);
+        assert_eq!(load(&mut store, 2).unwrap().unwrap().request_id, Some(8));
+        store
+    }
+
+    #[test]
+    fn record_round_trip() {
+        for msg in messages() {
+            let decoded = Message::decode(&record(&msg)).unwrap();
+            assert_eq!(record(&decoded), record(&msg));
+        }
+        let full = Message::new(&"t".repeat(TOPIC), [1u8; PAYLOAD], Some(1)).unwrap();
+        assert_eq!(record(&full).len(), R);
+        assert!(matches!(full.encode::<{ R - 1 }>(0), Err(Error::StorageError)));
+        assert_eq!(record_seq(&full.encode::<R>(0x01020304).unwrap()).unwrap(), 0x01020304);
+    }
+
+    #[test]
+    fn corrupt_record() {
+        let record = record(&messages()[0]);
+        assert!(matches!(Message::decode(&record[..RECORD_HEADER - 1]), Err(Error::ParseError)));
+        // Topic length past the end of the record
+        assert!(matches!(Message::decode(&record[..RECORD_HEADER + 2]), Err(Error::ParseError)));
+    }
+
+    #[test]
+    fn ram_backend() {
+        round_trip(RamBackend::<4, R>::new());
+    }
+
+    #[test]
+    fn ram_backend_progress_and_order() {
+        progress_and_order(RamBackend::<4, R>::new());
+    }
+
+    #[test]
+    fn ram_backend_corrupt_slot() {
+        let mut backend = RamBackend::<4, R>::new();
+        backend.write(1, &[1, 1]).unwrap();
+        let mut store = Store::<_, 4, R>::new(backend).unwrap();
+        assert!(matches!(load(&mut store, 1), Err(Error::ParseError)));
+        remove(&mut store, 1);
+        assert!(load(&mut store, 1).unwrap().is_none());
+        assert_eq!(store.store(&messages()[0]).unwrap(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_backend() {
+        let path = std::env::temp_dir().join(format!("mqtt-sn-store-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        round_trip(FileBackend::<R>::new(&path).unwrap());
+        let mut store = reopen(FileBackend::<R>::new(&path).unwrap());
+        remove(&mut store, 0);
+        drop(store);
+        let mut store = Store::<_, 4, R>::new(FileBackend::<R>::new(&path).unwrap()).unwrap();
+        assert!(load(&mut store, 0).unwrap().is_none());
+        assert!(load(&mut store, 2).unwrap().is_some());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_backend_progress_and_order() {
+        let path = std::env::temp_dir().join(format!("mqtt-sn-order-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        drop(progress_and_order(FileBackend::<R>::new(&path).unwrap()));
+        let mut store = Store::<_, 4, R>::new(FileBackend::<R>::new(&path).unwrap()).unwrap();
+        assert_eq!(order(&store), [1, 2, 0]);
+        let loaded = load(&mut store, 1).unwrap().unwrap();
+        assert_eq!((loaded.msg_id, loaded.received), (Some(0x1234), true));
+        assert_eq!(store.store(&messages()[0]).unwrap(), 3);
+        assert_eq!(order(&store), [1, 2, 0, 3]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn flash_backend() {
+        let mut flash = NorMock::new();
+        round_trip(FlashBackend::<_, R>::new(&mut flash, 256));
+        let mut store = reopen(FlashBackend::<_, R>::new(&mut flash, 256));
+        remove(&mut store, 0);
+        drop(store);
+        let mut store = Store::<_, 4, R>::new(FlashBackend::<_, R>::new(&mut flash, 256)).unwrap();
+        assert!(load(&mut store, 0).unwrap().is_none());
+        assert!(load(&mut store, 2).unwrap().is_some());
+    }
+
+    #[test]
+    fn flash_backend_progress_and_order() {
+        let mut flash = NorMock::new();
+        drop(progress_and_order(FlashBackend::<_, R>::new(&mut flash, 256)));
+        // One erase per stored message, progress and removal are written in place
+        assert_eq!(flash.erases, 4);
+        let mut store = Store::<_, 4, R>::new(FlashBackend::<_, R>::new(&mut flash, 256)).unwrap();
+        assert_eq!(order(&store), [1, 2, 0]);
+        let loaded = load(&mut store, 1).unwrap().unwrap();
+        assert_eq!((loaded.msg_id, loaded.received), (Some(0x1234), true));
+        // A rejected message gets a new msg_id, which needs an erase
+        let mut msg = loaded;
+        msg.msg_id = None;
+        store.update(1, &msg).unwrap();
+        assert_eq!(load(&mut store, 1).unwrap().unwrap().msg_id, None);
+        drop(store);
+        assert_eq!(flash.erases, 5);
+    }
+
+    #[test]
+    fn flash_backend_corrupt_slot() {
+        let mut flash = NorMock::new();
+        // Length of slot 1 larger than a record, slots take a sector each
+        let address = 256 + NorMock::ERASE_SIZE;
+        flash.memory[address..address + RECORD_LEN].copy_from_slice(&(R as u16 + 1).to_le_bytes());
+        let mut store = Store::<_, 4, R>::new(FlashBackend::<_, R>::new(&mut flash, 256)).unwrap();
+        assert!(matches!(load(&mut store, 1), Err(Error::StorageError)));
+        remove(&mut store, 1);
+        assert!(load(&mut store, 1).unwrap().is_none());
+        assert_eq!(store.store(&messages()[0]).unwrap(), 0);
+        assert_eq!(store.store(&messages()[0]).unwrap(), 1);
+        assert!(load(&mut store, 1).unwrap().is_some());
+    }
+}
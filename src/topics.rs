@@ -3,38 +3,65 @@ use crate::mqttsn::{MqttSnClientError, TopicIdType};
 
 type Error = MqttSnClientError;
 
-pub struct Topics {
-    store: FnvIndexMap<String<256>, (TopicIdType, u16), 16>
+/// Up to N topic names of at most T bytes, N must be a power of two
+pub struct Topics<const N: usize = 16, const T: usize = 256> {
+    store: FnvIndexMap<String<T>, (TopicIdType, u16), N>
 }
 
-impl Topics {
+impl<const N: usize, const T: usize> Topics<N, T> {
     pub fn new() -> Self {
         Self {
-            store: FnvIndexMap::<String<256>, (TopicIdType, u16), 16>::new()
+            store: FnvIndexMap::<String<T>, (TopicIdType, u16), N>::new()
         }
     }
     pub fn insert(
         &mut self,
-        topic: String<256>,
+        topic: String<T>,
         topic_type: TopicIdType,
         id: u16
     ) -> Result<(), Error> {
-        match self.get_by_id(id) {
-            Ok(topic) => {self.store.remove(&String::try_from(topic)?);},
+        match self.get_by_id(topic_type, id) {
+            Ok(topic) => {self.store.remove(&topic);},
             _ => ()
         }
         self.store.insert(topic, (topic_type, id)).map_err(|_|Error::TopicFailedInsert)?;
         Ok(())
     }
-    pub fn get_by_topic(&self, topic: &str) -> Option<&(TopicIdType, u16)> {
+    pub fn get_by_topic(&self, topic: &str) -> Option<(TopicIdType, u16)> {
         match String::try_from(topic) {
-            Ok(topic) => self.store.get(&topic),
+            Ok(topic) => self.store.get(&topic).copied()
+                .or_else(|| Some((TopicIdType::Short, Self::short_id(&topic)?))),
             _ => None
         }
     }
-    pub fn get_by_id(&self, id: u16) -> Result<&str, Error> {
-        if let Some((topic, _)) = self.store.iter().filter(|(_, (_, i))| i.clone() == id).next() {
-            return Ok(topic);
+    /// Forget a gateway assigned topic id, false if there was none
+    pub fn remove_registered(&mut self, topic: &str) -> bool {
+        match self.get_by_topic(topic) {
+            Some((TopicIdType::Id, _)) => {
+                self.store.retain(|t, _| t.as_str() != topic);
+                true
+            },
+            _ => false
+        }
+    }
+    /// Forget gateway assigned topic ids, predefined topics are kept
+    pub fn clear_registered(&mut self) {
+        self.store.retain(|_, (topic_type, _)| *topic_type != TopicIdType::Id);
+    }
+    /// Two character topic names are sent as short topic ids
+    pub fn short_id(topic: &str) -> Option<u16> {
+        match topic.as_bytes() {
+            &[a, b] => Some(u16::from_be_bytes([a, b])),
+            _ => None
+        }
+    }
+    pub fn get_by_id(&self, topic_type: TopicIdType, id: u16) -> Result<String<T>, Error> {
+        if topic_type == TopicIdType::Short {
+            let name = id.to_be_bytes();
+            return Ok(String::try_from(core::str::from_utf8(&name).map_err(|_| Error::ParseError)?)?);
+        }
+        if let Some((topic, _)) = self.store.iter().filter(|(_, entry)| **entry == (topic_type, id)).next() {
+            return Ok(topic.clone());
         } else {
             return Err(Error::TopicNotRegistered);
         }